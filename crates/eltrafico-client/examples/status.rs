@@ -0,0 +1,31 @@
+//! Print the status of eltrafico-tc and the events that follow
+//!
+//! `cargo run --example status -- /run/eltrafico-tc.sock` joins an instance started with
+//! `--socket`, `cargo run --example status -- target/debug/eltrafico-tc eth0` spawns one
+use eltrafico_client::{Client, Event, Result};
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let target = args
+        .first()
+        .ok_or("expected a socket or an eltrafico-tc binary")?;
+    let client = if target.ends_with(".sock") {
+        Client::connect(target)?
+    } else {
+        let client = Client::spawn(target)?;
+        client.set_interface(args.get(1).ok_or("expected an interface")?)?;
+        client
+    };
+    let events = client.subscribe();
+    println!("{:#?}", client.status()?);
+    for event in events {
+        println!("{event:?}");
+        if event == Event::Died || event == Event::Stopped {
+            break;
+        }
+        if let Event::Backend(_) = event {
+            println!("{:?}", client.programs()?);
+        }
+    }
+    Ok(())
+}
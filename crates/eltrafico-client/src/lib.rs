@@ -0,0 +1,405 @@
+//! Drive a privileged eltrafico-tc from a frontend
+//!
+//! [`Client`] spawns eltrafico-tc, or connects to one started with `--socket`, checks that it
+//! speaks our protocol and turns the lines it prints into typed replies and [`Event`]s.
+//! The setters only write a line and never block, the queries wait for their reply.
+pub mod netmonitor;
+#[cfg(feature = "async")]
+mod nonblocking;
+mod utils;
+
+pub use eltrafico_tc::ipc::{Dump, Message, ProgramStatus, Status, PROTOCOL_VERSION, SOCKET_PATH};
+pub use eltrafico_tc::{
+    ifconfig, Event as BackendEvent, Interface, InterfaceStatus, Kind, LimitConfig, ProgramInfo,
+    ProgramRule, Result, AUTO_INTERFACE,
+};
+
+pub use utils::{check_for_dependencies, find_eltrafico_tc};
+
+use log::{trace, warn};
+pub(crate) use serde::de::DeserializeOwned;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// How long a query waits for its reply
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The lines eltrafico-tc sends back to the frontend that asked
+const REPLIES: [&str; 4] = ["Hello", "Status", "Programs", "Dump"];
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Event {
+    /// Something happened in the shaping
+    Backend(BackendEvent),
+    /// eltrafico-tc cleaned up after a Stop message
+    Stopped,
+    /// eltrafico-tc went away without being stopped, nothing is shaped anymore
+    Died,
+}
+
+/// Gets the payload of a reply, `None` if eltrafico-tc went away first
+type Waiter = Box<dyn FnOnce(Option<String>) + Send>;
+/// Returns false once nobody listens anymore
+type Subscriber = Box<dyn FnMut(Event) -> bool + Send>;
+
+/// State shared with the thread reading the lines of eltrafico-tc
+#[derive(Default)]
+struct Shared {
+    /// waiters by kind of reply, replies come in the order of the queries
+    waiters: Mutex<HashMap<&'static str, VecDeque<Waiter>>>,
+    subscribers: Mutex<Vec<Subscriber>>,
+    alive: AtomicBool,
+}
+
+impl Shared {
+    fn wait_for(&self, reply: &'static str, waiter: Waiter) {
+        self.waiters
+            .lock()
+            .unwrap()
+            .entry(reply)
+            .or_default()
+            .push_back(waiter);
+    }
+
+    fn emit(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain_mut(|subscriber| subscriber(event.clone()));
+    }
+
+    fn handle_line(&self, line: &str) {
+        let line = line.trim();
+        if let Some(event) = BackendEvent::parse(line) {
+            self.emit(Event::Backend(event));
+            return;
+        }
+        let reply = line
+            .split_once(": ")
+            .and_then(|(kind, payload)| Some((REPLIES.iter().find(|r| **r == kind)?, payload)));
+        match reply {
+            Some((kind, payload)) => {
+                let waiter = self
+                    .waiters
+                    .lock()
+                    .unwrap()
+                    .get_mut(kind)
+                    .and_then(VecDeque::pop_front);
+                match waiter {
+                    Some(waiter) => waiter(Some(payload.to_string())),
+                    None => warn!("Nobody waits for the reply: {line}"),
+                }
+            }
+            None => trace!("ignoring line: {line}"),
+        }
+    }
+
+    /// Read the lines till eltrafico-tc goes away, then fail the queries still waiting
+    fn read(&self, reader: impl Read, mut child: Option<Child>) {
+        let mut stopped = false;
+        for line in BufReader::new(reader).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim() == "Stop" {
+                stopped = true;
+                continue;
+            }
+            self.handle_line(&line);
+        }
+        self.alive.store(false, Ordering::SeqCst);
+        if let Some(child) = &mut child {
+            let _ = child.wait();
+        }
+        for (_, waiters) in self.waiters.lock().unwrap().drain() {
+            for waiter in waiters {
+                waiter(None);
+            }
+        }
+        self.emit(if stopped { Event::Stopped } else { Event::Died });
+        // that was the last event, end the streams
+        self.subscribers.lock().unwrap().clear();
+    }
+
+    /// Subscribers that come after the last event get an ended stream
+    fn subscribe(&self, subscriber: Subscriber) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if self.alive.load(Ordering::SeqCst) {
+            subscribers.push(subscriber);
+        }
+    }
+}
+
+/// A connection to eltrafico-tc
+///
+/// Every method takes `&self`, so the client can be shared by the widgets of a frontend
+pub struct Client {
+    writer: Mutex<Box<dyn Write + Send>>,
+    shared: Arc<Shared>,
+}
+
+impl Client {
+    /// Run this eltrafico-tc binary, through pkexec unless we are root already
+    pub fn spawn(eltrafico_tc: impl AsRef<OsStr>) -> Result<Self> {
+        let command = if is_root() {
+            Command::new(eltrafico_tc)
+        } else {
+            let mut command = Command::new("pkexec");
+            command.arg(eltrafico_tc);
+            command
+        };
+        Self::spawn_command(command)
+    }
+
+    /// Run eltrafico-tc with a command of our own, its stdin and stdout are taken over
+    pub fn spawn_command(mut command: Command) -> Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or("eltrafico-tc has no stdin")?;
+        let stdout = child.stdout.take().ok_or("eltrafico-tc has no stdout")?;
+        Self::start(stdout, Box::new(stdin), Some(child))
+    }
+
+    /// Join an eltrafico-tc started with `--socket`, see [`SOCKET_PATH`]
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        Self::start(stream.try_clone()?, Box::new(stream), None)
+    }
+
+    fn start(
+        reader: impl Read + Send + 'static,
+        writer: Box<dyn Write + Send>,
+        child: Option<Child>,
+    ) -> Result<Self> {
+        let shared = Arc::new(Shared::default());
+        shared.alive.store(true, Ordering::SeqCst);
+        std::thread::spawn({
+            let shared = shared.clone();
+            move || shared.read(reader, child)
+        });
+        let client = Self {
+            writer: Mutex::new(writer),
+            shared,
+        };
+        // no timeout, pkexec can wait a long time for the password
+        let version: u32 = client
+            .request(&Message::Hello(PROTOCOL_VERSION), "Hello", None)?
+            .parse()?;
+        if version != PROTOCOL_VERSION {
+            return Err(format!(
+                "eltrafico-tc speaks version {version} of the protocol, we speak {PROTOCOL_VERSION}"
+            )
+            .into());
+        }
+        Ok(client)
+    }
+
+    /// False once eltrafico-tc stopped or died
+    pub fn is_alive(&self) -> bool {
+        self.shared.alive.load(Ordering::SeqCst)
+    }
+
+    /// A stream of the events from now on, it ends after `Stopped` or `Died`
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.shared
+            .subscribe(Box::new(move |event| tx.send(event).is_ok()));
+        rx
+    }
+
+    /// Write a raw message
+    pub fn send(&self, message: &Message) -> Result<()> {
+        if !self.is_alive() {
+            return Err("eltrafico-tc is not running".into());
+        }
+        let mut writer = self.writer.lock().unwrap();
+        writeln!(writer, "{message}")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Send a query and wait for the payload of its reply, `None` waits till eltrafico-tc goes away
+    fn request(
+        &self,
+        message: &Message,
+        reply: &'static str,
+        timeout: Option<Duration>,
+    ) -> Result<String> {
+        let (tx, rx) = mpsc::channel();
+        self.shared.wait_for(
+            reply,
+            Box::new(move |payload| {
+                let _ = tx.send(payload);
+            }),
+        );
+        self.send(message)?;
+        let payload = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match payload {
+            Ok(Some(payload)) => Ok(payload),
+            Ok(None) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err("eltrafico-tc went away before replying".into())
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                Err(format!("eltrafico-tc didn't answer {message}").into())
+            }
+        }
+    }
+
+    fn query<T: DeserializeOwned>(&self, message: &Message, reply: &'static str) -> Result<T> {
+        Ok(serde_json::from_str(&self.request(
+            message,
+            reply,
+            Some(REPLY_TIMEOUT),
+        )?)?)
+    }
+
+    /// Shape only this interface, [`AUTO_INTERFACE`] follows the default route
+    pub fn set_interface(&self, name: &str) -> Result<()> {
+        self.send(&Message::Interface(name.to_string()))
+    }
+
+    pub fn add_interface(&self, name: &str) -> Result<()> {
+        self.send(&Message::AddInterface(name.to_string()))
+    }
+
+    pub fn remove_interface(&self, name: &str) -> Result<()> {
+        self.send(&Message::RemoveInterface(name.to_string()))
+    }
+
+    pub fn set_global(&self, config: LimitConfig) -> Result<()> {
+        self.send(&Message::Global { config })
+    }
+
+    /// Limit the program on every shaped interface
+    pub fn set_program(&self, name: &str, config: LimitConfig) -> Result<()> {
+        self.set_program_rule(
+            name,
+            ProgramRule::Limit {
+                config,
+                interfaces: None,
+            },
+        )
+    }
+
+    pub fn set_program_rule(&self, name: &str, rule: ProgramRule) -> Result<()> {
+        let name = name.to_string();
+        self.send(&match rule {
+            ProgramRule::Block => Message::Block { name },
+            ProgramRule::Limit { config, interfaces } => Message::Program {
+                name,
+                config,
+                interfaces,
+            },
+        })
+    }
+
+    /// Drop all of the traffic of the program
+    pub fn block(&self, name: &str) -> Result<()> {
+        self.set_program_rule(name, ProgramRule::Block)
+    }
+
+    /// In allowlist mode only the traffic of allowed programs passes
+    pub fn set_allowlist(&self, on: bool) -> Result<()> {
+        self.send(&Message::Allowlist(on))
+    }
+
+    pub fn allow(&self, name: &str) -> Result<()> {
+        self.send(&Message::Allow {
+            name: name.to_string(),
+        })
+    }
+
+    pub fn disallow(&self, name: &str) -> Result<()> {
+        self.send(&Message::Disallow {
+            name: name.to_string(),
+        })
+    }
+
+    /// Clean up and quit, the subscribers get `Stopped` once that is done
+    pub fn stop(&self) -> Result<()> {
+        self.send(&Message::Stop)
+    }
+
+    pub fn status(&self) -> Result<Status> {
+        self.query(&Message::Status, "Status")
+    }
+
+    /// Every program seen so far with its rule, sorted by name
+    pub fn programs(&self) -> Result<Vec<ProgramStatus>> {
+        self.query(&Message::ListPrograms, "Programs")
+    }
+
+    /// What is actually applied in the kernel
+    pub fn dump(&self) -> Result<Dump> {
+        self.query(&Message::Dump, "Dump")
+    }
+}
+
+/// The effective uid is the second one in /proc/self/status
+fn is_root() -> bool {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let uids = status.lines().find_map(|l| l.strip_prefix("Uid:"))?;
+            Some(uids.split_whitespace().nth(1)? == "0")
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+fn fake_backend(script: &str) -> Result<Client> {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(script);
+    Client::spawn_command(command)
+}
+
+#[test]
+fn test_queries_and_events() {
+    let client = fake_backend(
+        r#"read hello; echo "Hello: 2"
+        read status; echo 'ProgramEntry: {"name":"firefox","pids":[1996],"exe":null,"cmdline":null}'
+        echo '{"interfaces":[],"auto_interface":false,"global_limit":{"download_rate":null,"download_minimum_rate":null,"upload_rate":null,"upload_minimum_rate":null,"download_priority":null,"upload_priority":null},"allowlist":true}' | sed 's/^/Status: /'
+        read stop; echo Stop"#,
+    )
+    .unwrap();
+    let events = client.subscribe();
+    let status = client.status().unwrap();
+    assert!(status.allowlist);
+    client.stop().unwrap();
+    assert_eq!(
+        events.iter().collect::<Vec<_>>(),
+        vec![
+            Event::Backend(BackendEvent::ProgramEntry(ProgramInfo {
+                name: "firefox".into(),
+                pids: vec![1996],
+                exe: None,
+                cmdline: None,
+            })),
+            Event::Stopped
+        ]
+    );
+    assert!(!client.is_alive());
+}
+
+#[test]
+fn test_backend_death() {
+    assert!(fake_backend(r#"read hello; echo "Hello: 1""#).is_err());
+
+    let client = fake_backend(r#"read hello; echo "Hello: 2"; read status"#).unwrap();
+    let events = client.subscribe();
+    assert!(client.status().is_err());
+    assert_eq!(events.recv(), Ok(Event::Died));
+    assert!(client.set_allowlist(true).is_err());
+}
@@ -0,0 +1,50 @@
+//! Live speeds of the programs, read from bandwhich or nethogs
+use crate::{check_for_dependencies, Result};
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::thread;
+mod bandwhich;
+mod nethogs;
+use bandwhich::bandwhich;
+use nethogs::nethogs;
+
+/// (up, down) in KB/sec
+pub type Speed = (f32, f32);
+
+/// One refresh of the monitor
+#[derive(Debug, Clone, Default)]
+pub struct Speeds {
+    /// the sum of the programs
+    pub global: Speed,
+    pub programs: HashMap<String, Speed>,
+}
+
+impl Speeds {
+    fn new(programs: HashMap<String, Speed>) -> Self {
+        // calculate the total download and upload rate
+        let global = programs.values().fold((0., 0.), |mut acc, (u, d)| {
+            acc.0 += u;
+            acc.1 += d;
+            acc
+        });
+        Self { global, programs }
+    }
+}
+
+/// Send the speeds every time the monitor refreshes, nothing is sent without bandwhich or nethogs
+pub fn netmonitor(tx: Sender<Speeds>) -> Result<()> {
+    if check_for_dependencies(&["bandwhich"]).is_ok() {
+        thread::spawn(|| {
+            if let Err(e) = bandwhich(tx) {
+                panic!("Bandwhich error: {}", e);
+            }
+        });
+    } else if check_for_dependencies(&["nethogs"]).is_ok() {
+        thread::spawn(|| {
+            if let Err(e) = nethogs(tx) {
+                panic!("Nethogs error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
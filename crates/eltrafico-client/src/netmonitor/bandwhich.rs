@@ -1,11 +1,11 @@
-use crate::gui::UpdateGuiMessage;
-use crate::CatchAll;
-use glib::Sender;
+use super::Speeds;
+use crate::Result;
 use std::collections::HashMap;
 use std::io::{self, BufRead};
 use std::process::{Command, Stdio};
+use std::sync::mpsc::Sender;
 
-pub fn bandwhich(tx: Sender<UpdateGuiMessage>) -> CatchAll<()> {
+pub fn bandwhich(tx: Sender<Speeds>) -> Result<()> {
     let mut cmd = Command::new("pkexec")
         .arg("bandwhich")
         .arg("-p")
@@ -16,7 +16,7 @@ pub fn bandwhich(tx: Sender<UpdateGuiMessage>) -> CatchAll<()> {
     let stdout = cmd
         .stdout
         .as_mut()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Err while reading nethogs output"))?;
+        .ok_or_else(|| io::Error::other("Err while reading nethogs output"))?;
 
     let mut stdout = std::io::BufReader::new(stdout);
     let mut raw_output = String::new();
@@ -38,16 +38,8 @@ pub fn bandwhich(tx: Sender<UpdateGuiMessage>) -> CatchAll<()> {
         // parse
         let parsed_data = parse_data(&raw_output);
 
-        // calculate the total download and upload rate
-        let global_speed = parsed_data.iter().fold((0., 0.), |mut acc, (_, (u, d))| {
-            acc.0 += u;
-            acc.1 += d;
-            acc
-        });
-
-        // send data to gui thread
-        tx.send(UpdateGuiMessage::CurrentGlobalSpeed(global_speed))?;
-        tx.send(UpdateGuiMessage::CurrentProgramSpeed(parsed_data))?;
+        // send data to the frontend
+        tx.send(Speeds::new(parsed_data))?;
 
         raw_output.clear();
     }
@@ -71,17 +63,3 @@ fn parse_data(raw_output: &str) -> HashMap<String, (f32, f32)> {
         })
         .collect()
 }
-
-#[test]
-fn t_bandwhich() {
-    gtk::init().unwrap();
-
-    let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
-    bandwhich(tx).unwrap();
-
-    rx.attach(None, move |message| {
-        dbg!(message);
-        glib::Continue(true)
-    });
-    gtk::main();
-}
@@ -0,0 +1,66 @@
+//! Queries as futures and events as a stream, with the `async` feature
+//!
+//! They only need to be polled, so they work with any executor
+use crate::{Client, Event, Message, ProgramStatus, Result, Status};
+use crate::{DeserializeOwned, Dump};
+use futures_channel::{mpsc, oneshot};
+
+impl Client {
+    /// A stream of the events from now on, it ends after `Stopped` or `Died`
+    pub fn subscribe_async(&self) -> mpsc::UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded();
+        self.shared
+            .subscribe(Box::new(move |event| tx.unbounded_send(event).is_ok()));
+        rx
+    }
+
+    async fn query_async<T: DeserializeOwned>(
+        &self,
+        message: &Message,
+        reply: &'static str,
+    ) -> Result<T> {
+        let (tx, rx) = oneshot::channel();
+        self.shared.wait_for(
+            reply,
+            Box::new(move |payload| {
+                let _ = tx.send(payload);
+            }),
+        );
+        self.send(message)?;
+        let payload = rx
+            .await
+            .ok()
+            .flatten()
+            .ok_or("eltrafico-tc went away before replying")?;
+        Ok(serde_json::from_str(&payload)?)
+    }
+
+    pub async fn status_async(&self) -> Result<Status> {
+        self.query_async(&Message::Status, "Status").await
+    }
+
+    pub async fn programs_async(&self) -> Result<Vec<ProgramStatus>> {
+        self.query_async(&Message::ListPrograms, "Programs").await
+    }
+
+    pub async fn dump_async(&self) -> Result<Dump> {
+        self.query_async(&Message::Dump, "Dump").await
+    }
+}
+
+#[test]
+fn test_async_queries() {
+    let client = crate::fake_backend(
+        r#"read hello; echo "Hello: 2"
+        read programs; echo 'Programs: [{"name":"curl","rule":"block","allowed":false,"idle":true}]'"#,
+    )
+    .unwrap();
+    let mut events = client.subscribe_async();
+    let programs = futures_executor::block_on(client.programs_async()).unwrap();
+    assert_eq!(programs[0].name, "curl");
+    assert_eq!(programs[0].rule, crate::ProgramRule::Block);
+    assert_eq!(
+        futures_executor::block_on(futures_util::StreamExt::next(&mut events)),
+        Some(Event::Died)
+    );
+}
@@ -0,0 +1,38 @@
+use crate::Result;
+
+pub fn check_for_dependencies(dependencies: &[&str]) -> std::result::Result<(), String> {
+    for tool in dependencies {
+        if let Err(e) = std::process::Command::new(tool)
+            // use -h so programs like nethogs dont stay open indefinitely
+            .arg("-h")
+            .output()
+        {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                return Err(format!("Missing program: {}", tool));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn find_eltrafico_tc() -> Result<String> {
+    // look for a specified custom path
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(pos) = args.iter().position(|a| a.as_str() == "--eltrafico-tc") {
+        let path = args.get(pos + 1).expect("Invalid eltrafico_tc path");
+        //pkexec require absolute path
+        let path = std::path::Path::new(path).canonicalize()?;
+        if !path.exists() {
+            panic!("Can't find {:?}", path);
+        }
+        Ok(path
+            .to_str()
+            .ok_or("Invalid eltrafico_tc path")
+            .map(ToString::to_string)?)
+    // look in $PATH
+    } else if check_for_dependencies(&["eltrafico_tc"]).is_ok() {
+        Ok("eltrafico_tc".into())
+    } else {
+        Err("Could not find eltrafico_tc in $PATH, you can sepecify a its location with --eltrafico-tc flag".into())
+    }
+}
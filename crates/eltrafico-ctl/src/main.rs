@@ -0,0 +1,307 @@
+use eltrafico_client::{Client, LimitConfig, ProgramRule, Result, AUTO_INTERFACE, SOCKET_PATH};
+
+const USAGE: &str = "Usage: eltrafico-ctl [--socket PATH] COMMAND
+
+Control the eltrafico-tc running with --socket, for scripts and cron jobs.
+Only root can use the socket, unless eltrafico-tc gave it to a group with --socket-group GROUP.
+
+Commands:
+    limit PROGRAM [LIMITS] [--interface NAME]...  Limit the program, only on these interfaces if given
+    global [LIMITS]                                Limit all of the traffic, no limits clears it
+    clear PROGRAM                                  Remove the limits and the block of the program
+    block PROGRAM                                  Drop all of the traffic of the program
+    interface NAME                                 Shape only this interface, auto follows the
+                                                   default route
+    status [--json]                                Print the shaped interfaces and the global limit
+    programs [--json]                              Print the programs seen so far and their rules
+
+Limits:
+    --down RATE, --up RATE          Maximum rates, like 2mbit or 500kbps
+    --down-min RATE, --up-min RATE  Guaranteed rates
+    --down-prio N, --up-prio N      Priorities, lower goes first
+
+Options:
+    --socket PATH  Where eltrafico-tc listens [default: /run/eltrafico-tc.sock]
+    -h, --help     Print this help";
+
+#[derive(PartialEq, Eq, Debug)]
+enum Command {
+    Limit {
+        name: String,
+        config: LimitConfig,
+        interfaces: Option<Vec<String>>,
+    },
+    Global(LimitConfig),
+    Clear(String),
+    Block(String),
+    Interface(String),
+    Status {
+        json: bool,
+    },
+    Programs {
+        json: bool,
+    },
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() || args.contains(&"-h".to_string()) || args.contains(&"--help".to_string()) {
+        println!("{USAGE}");
+        std::process::exit(0);
+    }
+    let result = parse_args(&args)
+        .map_err(Into::into)
+        .and_then(|(socket, command)| {
+            let socket = socket.as_deref().unwrap_or(SOCKET_PATH);
+            let client = Client::connect(socket).map_err(|e| {
+                format!("Can't reach eltrafico-tc, is it running with --socket? {socket}: {e}")
+            })?;
+            run(&client, command)
+        });
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// The socket path if given and the command
+fn parse_args(args: &[String]) -> std::result::Result<(Option<String>, Command), String> {
+    let mut socket = None;
+    let mut words = vec![];
+    let mut config = LimitConfig::default();
+    let mut interfaces: Option<Vec<String>> = None;
+    let mut json = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{arg} expects a value"))
+        };
+        let mut priority = || {
+            value()?
+                .parse()
+                .map_err(|_| format!("{arg} expects a number"))
+        };
+        match arg.as_str() {
+            "--socket" => socket = Some(value()?),
+            "--down" => config.download_rate = Some(value()?),
+            "--up" => config.upload_rate = Some(value()?),
+            "--down-min" => config.download_minimum_rate = Some(value()?),
+            "--up-min" => config.upload_minimum_rate = Some(value()?),
+            "--down-prio" => config.download_priority = Some(priority()?),
+            "--up-prio" => config.upload_priority = Some(priority()?),
+            "--interface" => interfaces.get_or_insert_with(Vec::new).push(value()?),
+            "--json" => json = true,
+            option if option.starts_with("--") => return Err(format!("Unknown option {option}")),
+            word => words.push(word),
+        }
+    }
+
+    let has_limits = config != LimitConfig::default() || interfaces.is_some();
+    let command = match words.as_slice() {
+        ["limit", name] => Command::Limit {
+            name: name.to_string(),
+            config,
+            interfaces,
+        },
+        ["global"] if interfaces.is_none() => Command::Global(config),
+        ["clear", name] if !has_limits => Command::Clear(name.to_string()),
+        ["block", name] if !has_limits => Command::Block(name.to_string()),
+        ["interface", name] if !has_limits => Command::Interface(name.to_string()),
+        ["status"] if !has_limits => Command::Status { json },
+        ["programs"] if !has_limits => Command::Programs { json },
+        [] => return Err("Expected a command, see --help".into()),
+        _ => return Err(format!("Invalid command: {}, see --help", words.join(" "))),
+    };
+    Ok((socket, command))
+}
+
+fn run(client: &Client, command: Command) -> Result<()> {
+    match command {
+        Command::Limit {
+            name,
+            config,
+            interfaces,
+        } => set_rule(client, &name, ProgramRule::Limit { config, interfaces }),
+        Command::Clear(name) => set_rule(client, &name, ProgramRule::default()),
+        Command::Block(name) => set_rule(client, &name, ProgramRule::Block),
+        Command::Global(config) => {
+            client.set_global(config.clone())?;
+            if client.status()?.global_limit != config {
+                return Err("eltrafico-tc rejected the global limit, see its log".into());
+            }
+            Ok(())
+        }
+        Command::Interface(name) => {
+            client.set_interface(&name)?;
+            let status = client.status()?;
+            let shaped = if name == AUTO_INTERFACE {
+                status.auto_interface
+            } else {
+                status.interfaces.iter().any(|i| i.name == name)
+            };
+            if !shaped {
+                return Err(format!("eltrafico-tc couldn't shape {name}, see its log").into());
+            }
+            Ok(())
+        }
+        Command::Status { json } => {
+            let status = client.status()?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+                return Ok(());
+            }
+            let mut interfaces: Vec<String> =
+                status.interfaces.iter().map(|i| i.name.clone()).collect();
+            if interfaces.is_empty() {
+                interfaces.push("none".into());
+            }
+            if status.auto_interface {
+                interfaces.push(format!("({AUTO_INTERFACE})"));
+            }
+            println!("Interfaces: {}", interfaces.join(" "));
+            println!("Global: {}", describe(&status.global_limit));
+            println!("Allowlist: {}", if status.allowlist { "on" } else { "off" });
+            Ok(())
+        }
+        Command::Programs { json } => {
+            let programs = client.programs()?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&programs)?);
+                return Ok(());
+            }
+            for program in programs {
+                let mut line = format!("{:<20} ", program.name);
+                match &program.rule {
+                    ProgramRule::Block => line.push_str("blocked"),
+                    ProgramRule::Limit { config, interfaces } => {
+                        line.push_str(&describe(config));
+                        if let Some(interfaces) = interfaces {
+                            line.push_str(&format!(" on {}", interfaces.join(",")));
+                        }
+                    }
+                }
+                if program.allowed {
+                    line.push_str(" [allowed]");
+                }
+                if program.idle {
+                    line.push_str(" [idle]");
+                }
+                println!("{line}");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Set the rule and check that it was applied
+///
+/// The query is answered after the rule is handled, a rule that tc rejected is rolled back
+fn set_rule(client: &Client, name: &str, rule: ProgramRule) -> Result<()> {
+    client.set_program_rule(name, rule.clone())?;
+    let applied = client
+        .programs()?
+        .into_iter()
+        .any(|program| program.name == name && program.rule == rule);
+    if !applied {
+        return Err(format!("eltrafico-tc rejected the rule of {name}, see its log").into());
+    }
+    Ok(())
+}
+
+fn describe(config: &LimitConfig) -> String {
+    let parts: Vec<String> = [
+        ("down", config.download_rate.clone()),
+        ("up", config.upload_rate.clone()),
+        ("down min", config.download_minimum_rate.clone()),
+        ("up min", config.upload_minimum_rate.clone()),
+        ("down prio", config.download_priority.map(|p| p.to_string())),
+        ("up prio", config.upload_priority.map(|p| p.to_string())),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some(format!("{name} {}", value?)))
+    .collect();
+    if parts.is_empty() {
+        "no limit".into()
+    } else {
+        parts.join(", ")
+    }
+}
+
+#[cfg(test)]
+fn args(line: &str) -> Vec<String> {
+    line.split_whitespace().map(ToString::to_string).collect()
+}
+
+#[test]
+fn test_parse_args() {
+    assert_eq!(
+        parse_args(&args("limit firefox --down 2mbit --up 500kbit")),
+        Ok((
+            None,
+            Command::Limit {
+                name: "firefox".into(),
+                config: LimitConfig {
+                    download_rate: Some("2mbit".into()),
+                    upload_rate: Some("500kbit".into()),
+                    ..Default::default()
+                },
+                interfaces: None,
+            }
+        ))
+    );
+    assert_eq!(
+        parse_args(&args(
+            "--socket /tmp/s limit curl --up-prio 2 --interface eth0"
+        )),
+        Ok((
+            Some("/tmp/s".into()),
+            Command::Limit {
+                name: "curl".into(),
+                config: LimitConfig {
+                    upload_priority: Some(2),
+                    ..Default::default()
+                },
+                interfaces: Some(vec!["eth0".into()]),
+            }
+        ))
+    );
+    assert_eq!(
+        parse_args(&args("global --down 50mbit")),
+        Ok((
+            None,
+            Command::Global(LimitConfig {
+                download_rate: Some("50mbit".into()),
+                ..Default::default()
+            })
+        ))
+    );
+    assert_eq!(
+        parse_args(&args("clear firefox")),
+        Ok((None, Command::Clear("firefox".into())))
+    );
+    assert_eq!(
+        parse_args(&args("status --json")),
+        Ok((None, Command::Status { json: true }))
+    );
+    assert!(parse_args(&args("limit")).is_err());
+    assert!(parse_args(&args("clear firefox --down 1mbit")).is_err());
+    assert!(parse_args(&args("limit firefox --down-prio high")).is_err());
+    assert!(parse_args(&args("limit firefox --down")).is_err());
+    assert!(parse_args(&args("global --fast")).is_err());
+}
+
+#[test]
+fn test_describe() {
+    assert_eq!(describe(&LimitConfig::default()), "no limit");
+    assert_eq!(
+        describe(&LimitConfig {
+            download_rate: Some("2mbit".into()),
+            upload_priority: Some(1),
+            ..Default::default()
+        }),
+        "down 2mbit, up prio 1"
+    );
+}
@@ -0,0 +1,146 @@
+use crate::journal::{self, Entry};
+use crate::utils::{self, ifconfig, Kind};
+use crate::{run, Result};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Prefix of the IFB devices we create, the kernel limits interface names to 15 characters
+pub const IFB_PREFIX: &str = "eltrafico-ifb";
+
+/// Set if the ifb kernel module was loaded by us, so we unload it after removing our last device
+static LOADED_IFB_MODULE: AtomicBool = AtomicBool::new(false);
+
+/// An IFB device used to shape the ingress traffic of an interface
+///
+/// It is released when dropped: removed if we created it, brought down if we only activated it
+pub struct IfbDevice {
+    pub name: String,
+    created: bool,
+    released: bool,
+}
+
+impl IfbDevice {
+    /// Create a new uniquely named IFB device
+    ///
+    /// If that fails, fall back to activating an existing IFB device that is down,
+    /// `used` are the devices already used by other shaped interfaces
+    pub fn acquire(used: &[String]) -> Result<Self> {
+        match Self::create() {
+            Ok(device) => Ok(device),
+            Err(e) => {
+                log::warn!("Failed to create an IFB device: {e}");
+                let interface = ifconfig()?
+                    .into_iter()
+                    .find(|i| i.kind == Kind::Ifb && !i.is_up() && !used.contains(&i.name))
+                    .ok_or("No IFB device available")?;
+                log::info!("Using the existing IFB device {}", interface.name);
+                let mut device = Self {
+                    name: interface.name,
+                    created: false,
+                    released: false,
+                };
+                journal::record(Entry::ActivatedIfb(device.name.clone()));
+                device.activate()?;
+                Ok(device)
+            }
+        }
+    }
+
+    fn create() -> Result<Self> {
+        let before: HashSet<String> = ifconfig()?.into_iter().map(|i| i.name).collect();
+        if !Path::new("/sys/module/ifb").exists() {
+            // don't let the module create its own ifb0, it can also be built into the kernel
+            match utils::run("modprobe ifb numifbs=0".into()) {
+                Ok(output) if output.status.success() => {
+                    LOADED_IFB_MODULE.store(true, Ordering::SeqCst)
+                }
+                Ok(_) => (),
+                Err(e) => log::warn!("Failed to run modprobe: {e}"),
+            }
+        }
+        let name = free_ifb_name(&before).ok_or_else(|| {
+            format!("Ran out of IFB device names, {IFB_PREFIX}0 to {IFB_PREFIX}99 are taken")
+        })?;
+        journal::record(Entry::CreatedIfb(name.clone()));
+        run!("ip link add {name} type ifb")?;
+        let after: HashSet<String> = ifconfig()?.into_iter().map(|i| i.name).collect();
+        if !after.contains(&name) {
+            journal::record_removal(Entry::CreatedIfb(name.clone()));
+            return Err(format!("Error creating IFB device {name}").into());
+        }
+
+        let mut device = Self {
+            name,
+            created: true,
+            released: false,
+        };
+        device.activate()?;
+        Ok(device)
+    }
+
+    fn activate(&mut self) -> Result<()> {
+        run!("ip link set dev {} up", self.name)
+    }
+
+    /// Keep the device after we are gone
+    pub fn detach(&mut self) {
+        self.released = true;
+    }
+
+    /// Remove the device if we created it, otherwise bring it back down
+    pub fn release(&mut self) -> Result<()> {
+        if self.released {
+            return Ok(());
+        }
+        self.released = true;
+
+        log::info!("Cleaning up IFB device {}", self.name);
+        if self.created {
+            run!("ip link del dev {}", self.name)?;
+            journal::record_removal(Entry::CreatedIfb(self.name.clone()));
+        } else {
+            run!("ip link set dev {} down", self.name)?;
+            journal::record_removal(Entry::ActivatedIfb(self.name.clone()));
+        }
+        if LOADED_IFB_MODULE.load(Ordering::SeqCst)
+            && !ifconfig()?.iter().any(|i| i.kind == Kind::Ifb)
+        {
+            run!("rmmod ifb")?;
+            LOADED_IFB_MODULE.store(false, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for IfbDevice {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            log::warn!("Failed to release IFB device {}: {e}", self.name);
+        }
+    }
+}
+
+/// Interface names are at most IFNAMSIZ - 1 bytes, so `eltrafico-ifb99` is the last one
+const MAX_INTERFACE_NAME: usize = 15;
+
+fn free_ifb_name(existing: &HashSet<String>) -> Option<String> {
+    (0..)
+        .map(|n| format!("{IFB_PREFIX}{n}"))
+        .take_while(|name| name.len() <= MAX_INTERFACE_NAME)
+        .find(|name| !existing.contains(name))
+}
+
+#[test]
+fn test_free_ifb_name() {
+    let mut existing = HashSet::new();
+    assert_eq!(free_ifb_name(&existing).unwrap(), "eltrafico-ifb0");
+    existing.insert("eltrafico-ifb0".to_string());
+    existing.insert("ifb0".to_string());
+    assert_eq!(free_ifb_name(&existing).unwrap(), "eltrafico-ifb1");
+    // eltrafico-ifb100 is too long for the kernel
+    existing.extend((0..99).map(|n| format!("eltrafico-ifb{n}")));
+    assert_eq!(free_ifb_name(&existing).unwrap(), "eltrafico-ifb99");
+    existing.insert("eltrafico-ifb99".to_string());
+    assert_eq!(free_ifb_name(&existing), None);
+}
@@ -1,4 +1,19 @@
-#[derive(Eq, PartialEq, Debug, Clone, Default)]
+use crate::kernel::Handle;
+use crate::shaper::ProgramRule;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Version of the line protocol, exchanged with `Hello`
+///
+/// Frontends that never say `Hello` get the events of version 1, where `ProgramEntry` is only
+/// the name
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Where `eltrafico-tc --socket` listens for frontends
+pub const SOCKET_PATH: &str = "/run/eltrafico-tc.sock";
+
+#[derive(Eq, PartialEq, Debug, Clone, Default, Serialize, Deserialize)]
 pub struct LimitConfig {
     pub download_rate: Option<String>,
     pub download_minimum_rate: Option<String>,
@@ -8,12 +23,106 @@ pub struct LimitConfig {
     pub upload_priority: Option<usize>,
 }
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Eq, PartialEq, Debug, Clone)]
 pub enum Message {
+    /// Handshake with the protocol version of the frontend, answered with ours
+    Hello(u32),
     Stop,
     Interface(String),
-    Global { config: LimitConfig },
-    Program { name: String, config: LimitConfig },
+    AddInterface(String),
+    RemoveInterface(String),
+    Global {
+        config: LimitConfig,
+    },
+    /// `interfaces` restricts the limit to these interfaces, `None` means every shaped interface
+    ///
+    /// The name is a json string on the line if it has whitespace, like `Web Content`
+    Program {
+        name: String,
+        config: LimitConfig,
+        interfaces: Option<Vec<String>>,
+    },
+    Block {
+        name: String,
+    },
+    Allowlist(bool),
+    Allow {
+        name: String,
+    },
+    Disallow {
+        name: String,
+    },
+    /// Reply with a `Status` line
+    Status,
+    /// Reply with a `Programs` line
+    ListPrograms,
+    /// Reply with a `Dump` line
+    Dump,
+}
+
+/// Reply to `Status`: `Status: {json}`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Status {
+    pub interfaces: Vec<InterfaceStatus>,
+    /// the shaping follows the default route
+    pub auto_interface: bool,
+    pub global_limit: LimitConfig,
+    pub allowlist: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InterfaceStatus {
+    pub name: String,
+    /// the incomming traffic of the interface is shaped on it
+    pub ifb_device: String,
+    pub ingress_qdisc: Handle,
+    pub egress_qdisc: Handle,
+}
+
+/// An entry of the reply to `ListPrograms`: `Programs: [{json}, ...]`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProgramStatus {
+    pub name: String,
+    pub rule: ProgramRule,
+    pub allowed: bool,
+    /// the program has been gone for longer than the idle timeout
+    pub idle: bool,
+}
+
+/// The event of a new program: `ProgramEntry: {json}`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProgramInfo {
+    /// the `comm` name reported by ss, the key of the rules
+    pub name: String,
+    /// the processes with connections, sorted
+    pub pids: Vec<u32>,
+    /// the executable of the first process
+    pub exe: Option<String>,
+    /// the arguments of the first process, separated by spaces
+    pub cmdline: Option<String>,
+}
+
+/// Reply to `Dump`: `Dump: {json}`, what is actually applied in the kernel
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Dump {
+    pub status: Status,
+    pub programs: Vec<ProgramStatus>,
+    pub interfaces: Vec<InterfaceDump>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InterfaceDump {
+    pub name: String,
+    /// htb classes of the limited programs
+    pub classes: BTreeMap<String, Directions<Option<Handle>>>,
+    /// ports with a filter, ports of connections that are gone are under `?`
+    pub filtered_ports: BTreeMap<String, Directions<Vec<usize>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct Directions<T> {
+    pub ingress: T,
+    pub egress: T,
 }
 
 impl TryFrom<String> for Message {
@@ -31,9 +140,21 @@ impl TryFrom<String> for Message {
             use Message::*;
             match msg.trim() {
                 "Stop" => Some(Stop),
+                msg if msg.starts_with("Hello: ") => {
+                    Some(Hello(msg.split("Hello: ").nth(1)?.trim().parse().ok()?))
+                }
+                "Status" => Some(Status),
+                "ListPrograms" => Some(ListPrograms),
+                "Dump" => Some(Dump),
                 msg if msg.starts_with("Interface: ") => {
                     Some(Interface(msg.split("Interface: ").nth(1)?.to_string()))
                 }
+                msg if msg.starts_with("AddInterface: ") => Some(AddInterface(
+                    msg.split("AddInterface: ").nth(1)?.trim().to_string(),
+                )),
+                msg if msg.starts_with("RemoveInterface: ") => Some(RemoveInterface(
+                    msg.split("RemoveInterface: ").nth(1)?.trim().to_string(),
+                )),
                 msg if msg.starts_with("Global: ") => {
                     let mut msg = msg.split("Global: ").nth(1)?.split_whitespace();
                     let download_rate = parse_part(msg.next());
@@ -54,16 +175,19 @@ impl TryFrom<String> for Message {
                     })
                 }
                 msg if msg.starts_with("Program: ") => {
-                    let mut msg = msg.split("Program: ").nth(1)?.split_whitespace();
-                    let name = msg.next()?.to_string();
+                    let (name, msg) = split_name(msg.split("Program: ").nth(1)?)?;
+                    let mut msg = msg.split_whitespace();
                     let download_rate = parse_part(msg.next());
                     let upload_rate = parse_part(msg.next());
                     let download_minimum_rate = parse_part(msg.next());
                     let upload_minimum_rate = parse_part(msg.next());
                     let download_priority = parse_part(msg.next());
                     let upload_priority = parse_part(msg.next());
+                    let interfaces = parse_part(msg.next())
+                        .map(|interfaces| interfaces.split(',').map(ToString::to_string).collect());
                     Some(Program {
                         name,
+                        interfaces,
                         config: LimitConfig {
                             download_rate,
                             download_minimum_rate,
@@ -74,6 +198,22 @@ impl TryFrom<String> for Message {
                         },
                     })
                 }
+                msg if msg.starts_with("Block: ") => Some(Block {
+                    name: msg.split("Block: ").nth(1)?.trim().to_string(),
+                }),
+                msg if msg.starts_with("Allowlist: ") => {
+                    match msg.split("Allowlist: ").nth(1)?.trim() {
+                        "on" => Some(Allowlist(true)),
+                        "off" => Some(Allowlist(false)),
+                        _ => None,
+                    }
+                }
+                msg if msg.starts_with("Allow: ") => Some(Allow {
+                    name: msg.split("Allow: ").nth(1)?.trim().to_string(),
+                }),
+                msg if msg.starts_with("Disallow: ") => Some(Disallow {
+                    name: msg.split("Disallow: ").nth(1)?.trim().to_string(),
+                }),
                 _ => None,
             }
         };
@@ -81,6 +221,74 @@ impl TryFrom<String> for Message {
     }
 }
 
+/// The line a frontend sends, the inverse of parsing it
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let part = |part: &Option<String>| part.clone().unwrap_or_else(|| "None".into());
+        let priority = |priority: Option<usize>| part(&priority.map(|p| p.to_string()));
+        let limits = |config: &LimitConfig| {
+            format!(
+                "{} {} {} {} {} {}",
+                part(&config.download_rate),
+                part(&config.upload_rate),
+                part(&config.download_minimum_rate),
+                part(&config.upload_minimum_rate),
+                priority(config.download_priority),
+                priority(config.upload_priority),
+            )
+        };
+        use Message::*;
+        match self {
+            Hello(version) => write!(f, "Hello: {version}"),
+            Stop => write!(f, "Stop"),
+            Interface(name) => write!(f, "Interface: {name}"),
+            AddInterface(name) => write!(f, "AddInterface: {name}"),
+            RemoveInterface(name) => write!(f, "RemoveInterface: {name}"),
+            Global { config } => write!(f, "Global: {}", limits(config)),
+            Program {
+                name,
+                config,
+                interfaces,
+            } => write!(
+                f,
+                "Program: {} {} {}",
+                quote_name(name),
+                limits(config),
+                part(&interfaces.as_ref().map(|interfaces| interfaces.join(",")))
+            ),
+            Block { name } => write!(f, "Block: {name}"),
+            Allowlist(on) => write!(f, "Allowlist: {}", if *on { "on" } else { "off" }),
+            Allow { name } => write!(f, "Allow: {name}"),
+            Disallow { name } => write!(f, "Disallow: {name}"),
+            Status => write!(f, "Status"),
+            ListPrograms => write!(f, "ListPrograms"),
+            Dump => write!(f, "Dump"),
+        }
+    }
+}
+
+/// The name as a json string if splitting the line on whitespace would cut it
+fn quote_name(name: &str) -> String {
+    if name.is_empty() || name.starts_with('"') || name.contains(char::is_whitespace) {
+        serde_json::to_string(name).expect("a string serializes")
+    } else {
+        name.to_string()
+    }
+}
+
+/// The name at the start of the line, quoted or not, and the rest of the line
+fn split_name(line: &str) -> Option<(String, &str)> {
+    let line = line.trim_start();
+    if line.starts_with('"') {
+        let mut names = serde_json::Deserializer::from_str(line).into_iter::<String>();
+        let name = names.next()?.ok()?;
+        Some((name, &line[names.byte_offset()..]))
+    } else {
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        (!name.is_empty()).then(|| (name.to_string(), rest))
+    }
+}
+
 #[test]
 fn test_parse_message() {
     assert_eq!(
@@ -94,7 +302,32 @@ fn test_parse_message() {
                 upload_minimum_rate: None,
                 download_priority: None,
                 upload_priority: None,
-            }
+            },
+            interfaces: None,
+        })
+    );
+    assert_eq!(
+        "Program: firefox 100kbps None None None None None eth0,wlan0"
+            .to_string()
+            .try_into(),
+        Ok(Message::Program {
+            name: "firefox".into(),
+            config: LimitConfig {
+                download_rate: Some("100kbps".into()),
+                ..Default::default()
+            },
+            interfaces: Some(vec!["eth0".into(), "wlan0".into()]),
+        })
+    );
+    assert_eq!(
+        r#"Program: "Web Content" 2mbit"#.to_string().try_into(),
+        Ok(Message::Program {
+            name: "Web Content".into(),
+            config: LimitConfig {
+                download_rate: Some("2mbit".into()),
+                ..Default::default()
+            },
+            interfaces: None,
         })
     );
     assert_eq!(
@@ -114,5 +347,138 @@ fn test_parse_message() {
         "Interface: wlan0".to_string().try_into(),
         Ok(Message::Interface("wlan0".into()))
     );
+    assert_eq!(
+        "Block: firefox".to_string().try_into(),
+        Ok(Message::Block {
+            name: "firefox".into()
+        })
+    );
+    assert_eq!(
+        "Allowlist: on".to_string().try_into(),
+        Ok(Message::Allowlist(true))
+    );
+    assert!(Message::try_from("Allowlist: maybe".to_string()).is_err());
+    assert_eq!(
+        "Allow: firefox".to_string().try_into(),
+        Ok(Message::Allow {
+            name: "firefox".into()
+        })
+    );
+    assert_eq!(
+        "Disallow: firefox".to_string().try_into(),
+        Ok(Message::Disallow {
+            name: "firefox".into()
+        })
+    );
+    assert_eq!(
+        "AddInterface: eth0".to_string().try_into(),
+        Ok(Message::AddInterface("eth0".into()))
+    );
+    assert_eq!(
+        "RemoveInterface: eth0".to_string().try_into(),
+        Ok(Message::RemoveInterface("eth0".into()))
+    );
     assert_eq!("Stop".to_string().try_into(), Ok(Message::Stop));
+    assert_eq!("Status".to_string().try_into(), Ok(Message::Status));
+    assert_eq!(
+        "ListPrograms".to_string().try_into(),
+        Ok(Message::ListPrograms)
+    );
+    assert_eq!("Dump".to_string().try_into(), Ok(Message::Dump));
+    assert_eq!("Hello: 1".to_string().try_into(), Ok(Message::Hello(1)));
+}
+
+#[test]
+fn test_display_message() {
+    let messages = [
+        Message::Hello(PROTOCOL_VERSION),
+        Message::Interface("eth0".into()),
+        Message::Global {
+            config: LimitConfig {
+                upload_rate: Some("10kbps".into()),
+                download_priority: Some(2),
+                ..Default::default()
+            },
+        },
+        Message::Program {
+            name: "firefox".into(),
+            config: LimitConfig {
+                download_rate: Some("100kbps".into()),
+                ..Default::default()
+            },
+            interfaces: Some(vec!["eth0".into(), "wlan0".into()]),
+        },
+        Message::Program {
+            name: "firefox".into(),
+            config: LimitConfig::default(),
+            interfaces: None,
+        },
+        Message::Program {
+            name: "Web Content".into(),
+            config: LimitConfig::default(),
+            interfaces: None,
+        },
+        Message::Program {
+            name: r#""quoted" \ name"#.into(),
+            config: LimitConfig::default(),
+            interfaces: None,
+        },
+        Message::Allowlist(false),
+        Message::Block {
+            name: "curl".into(),
+        },
+        Message::Dump,
+    ];
+    for message in messages {
+        assert_eq!(Message::try_from(message.to_string()), Ok(message));
+    }
+    assert_eq!(
+        Message::Global {
+            config: LimitConfig::default()
+        }
+        .to_string(),
+        "Global: None None None None None None"
+    );
+}
+
+#[test]
+fn test_serialize_replies() {
+    let status = Status {
+        interfaces: vec![InterfaceStatus {
+            name: "eth0".into(),
+            ifb_device: "ifb0".into(),
+            ingress_qdisc: Handle::new(1, 0),
+            egress_qdisc: Handle::new(10, 0),
+        }],
+        auto_interface: false,
+        global_limit: LimitConfig {
+            download_rate: Some("1mbit".into()),
+            ..Default::default()
+        },
+        allowlist: false,
+    };
+    assert_eq!(
+        serde_json::to_string(&status).unwrap(),
+        r#"{"interfaces":[{"name":"eth0","ifb_device":"ifb0","ingress_qdisc":"1:","egress_qdisc":"a:"}],"auto_interface":false,"global_limit":{"download_rate":"1mbit","download_minimum_rate":null,"upload_rate":null,"upload_minimum_rate":null,"download_priority":null,"upload_priority":null},"allowlist":false}"#
+    );
+    let programs = [
+        ProgramStatus {
+            name: "curl".into(),
+            rule: ProgramRule::Block,
+            allowed: false,
+            idle: true,
+        },
+        ProgramStatus {
+            name: "firefox".into(),
+            rule: ProgramRule::Limit {
+                config: LimitConfig::default(),
+                interfaces: Some(vec!["eth0".into()]),
+            },
+            allowed: true,
+            idle: false,
+        },
+    ];
+    let json = serde_json::to_string(&programs).unwrap();
+    assert!(json.starts_with(r#"[{"name":"curl","rule":"block","allowed":false,"idle":true},{"name":"firefox","rule":{"limit":{"config":{"#));
+    assert!(json.ends_with(r#""interfaces":["eth0"]}},"allowed":true,"idle":false}]"#));
 }
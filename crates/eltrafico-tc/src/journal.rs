@@ -0,0 +1,231 @@
+//! Journal of the kernel objects we create, so a crashed run can be cleaned up later
+//!
+//! Only the objects that own the rest are recorded: removing a root qdisc removes its classes and
+//! filters, removing the ingress qdisc removes the mirred redirect.
+//! Objects are recorded before they are created and their removal after they are removed,
+//! so the journal never misses something that might exist.
+use crate::tc::RootQDiscSnapshot;
+use crate::{run, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+/// `/run` is cleared on reboot, just like the objects recorded in the journal
+pub const JOURNAL_PATH: &str = "/run/eltrafico-tc.journal";
+
+/// The open journal and its path
+static JOURNAL: Mutex<Option<(File, String)>> = Mutex::new(None);
+
+/// Journal of the cap `eltrafico-tc global` leaves on the interface, `clear` recovers it
+pub fn global_cap_path(interface: &str) -> String {
+    format!("/run/eltrafico-tc.{interface}.journal")
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Entry {
+    /// Root qdisc of a device
+    RootQDisc(String),
+    /// Ingress qdisc of a device
+    IngressQDisc(String),
+    /// Root qdisc that was there before ours
+    ReplacedRootQDisc(RootQDiscSnapshot),
+    /// IFB device created by us
+    CreatedIfb(String),
+    /// Existing IFB device brought up by us
+    ActivatedIfb(String),
+}
+
+impl Entry {
+    fn serialize(&self) -> String {
+        match self {
+            Entry::RootQDisc(device) => format!("root {device}"),
+            Entry::IngressQDisc(device) => format!("ingress {device}"),
+            Entry::ReplacedRootQDisc(RootQDiscSnapshot {
+                device,
+                kind,
+                handle,
+                options,
+            }) => format!("replaced-root {device} {kind} {handle} {options}"),
+            Entry::CreatedIfb(device) => format!("created-ifb {device}"),
+            Entry::ActivatedIfb(device) => format!("activated-ifb {device}"),
+        }
+    }
+
+    fn parse(entry: &str) -> Option<Self> {
+        let (kind, device) = entry.split_once(' ')?;
+        let device = device.to_string();
+        match kind {
+            "root" => Some(Entry::RootQDisc(device)),
+            "ingress" => Some(Entry::IngressQDisc(device)),
+            "replaced-root" => {
+                let mut fields = device.splitn(4, ' ');
+                Some(Entry::ReplacedRootQDisc(RootQDiscSnapshot {
+                    device: fields.next()?.to_string(),
+                    kind: fields.next()?.to_string(),
+                    handle: fields.next()?.parse().ok()?,
+                    options: fields.next().unwrap_or_default().to_string(),
+                }))
+            }
+            "created-ifb" => Some(Entry::CreatedIfb(device)),
+            "activated-ifb" => Some(Entry::ActivatedIfb(device)),
+            _ => None,
+        }
+    }
+
+    /// Remove the object from the kernel
+    fn undo(&self) -> Result<()> {
+        match self {
+            Entry::RootQDisc(device) => run!("tc qdisc del dev {device} root"),
+            Entry::IngressQDisc(device) => run!("tc qdisc del dev {device} ingress"),
+            Entry::ReplacedRootQDisc(snapshot) => snapshot.restore(),
+            Entry::CreatedIfb(device) => run!("ip link del dev {device}"),
+            Entry::ActivatedIfb(device) => run!("ip link set dev {device} down"),
+        }
+    }
+}
+
+/// Record an object we are about to create
+pub fn record(entry: Entry) {
+    write_line(&format!("+ {}", entry.serialize()));
+}
+
+/// Record an object we just removed
+pub fn record_removal(entry: Entry) {
+    write_line(&format!("- {}", entry.serialize()));
+}
+
+fn write_line(line: &str) {
+    let mut journal = JOURNAL.lock().unwrap();
+    let Some((file, _)) = journal.as_mut() else {
+        return;
+    };
+    if let Err(e) = writeln!(file, "{line}").and_then(|_| file.sync_data()) {
+        log::warn!("Failed to write to the journal: {e}");
+    }
+}
+
+/// The pid of the run that wrote the journal and the objects it left behind, oldest first
+fn parse_journal(journal: &str) -> (Option<u32>, Vec<Entry>) {
+    let mut lines = journal.lines();
+    let pid = lines
+        .next()
+        .and_then(|line| line.strip_prefix("pid "))
+        .and_then(|pid| pid.parse().ok());
+    let mut entries: Vec<Entry> = vec![];
+    for line in lines {
+        if let Some(entry) = line.strip_prefix("+ ").and_then(Entry::parse) {
+            entries.push(entry);
+        } else if let Some(entry) = line.strip_prefix("- ").and_then(Entry::parse) {
+            if let Some(pos) = entries.iter().rposition(|e| *e == entry) {
+                entries.remove(pos);
+            }
+        }
+    }
+    (pid, entries)
+}
+
+/// A killed process that wasn't reaped yet is a zombie, it doesn't count as running
+fn is_eltrafico_tc_running(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return false;
+    }
+    // the format is `pid (comm) state ...`
+    let Ok(stat) = std::fs::read_to_string(format!("/proc/{pid}/stat")) else {
+        return false;
+    };
+    let Some((comm, rest)) = stat
+        .split_once('(')
+        .and_then(|(_, rest)| rest.rsplit_once(')'))
+    else {
+        return false;
+    };
+    comm.starts_with("eltrafico") && !rest.trim_start().starts_with('Z')
+}
+
+/// Remove what a previous run left behind, newest objects first
+///
+/// Fails if the run that wrote the journal is still alive
+pub fn recover() -> Result<()> {
+    recover_at(JOURNAL_PATH)
+}
+
+pub fn recover_at(path: &str) -> Result<()> {
+    let Ok(journal) = std::fs::read_to_string(path) else {
+        return Ok(());
+    };
+    let (pid, entries) = parse_journal(&journal);
+    if let Some(pid) = pid.filter(|pid| is_eltrafico_tc_running(*pid)) {
+        return Err(format!("eltrafico-tc is already running with pid {pid}").into());
+    }
+    if !entries.is_empty() {
+        log::warn!("Cleaning up the leftovers of a previous run: {entries:?}");
+    }
+    for entry in entries.iter().rev() {
+        entry.undo()?;
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Recover from a previous run and start a new journal
+pub fn open() -> Result<()> {
+    open_at(JOURNAL_PATH)
+}
+
+pub fn open_at(path: &str) -> Result<()> {
+    recover_at(path)?;
+    let mut file = OpenOptions::new().create_new(true).write(true).open(path)?;
+    writeln!(file, "pid {}", std::process::id())?;
+    file.sync_data()?;
+    *JOURNAL.lock().unwrap() = Some((file, path.to_string()));
+    Ok(())
+}
+
+/// Stop journaling, the journal is removed if everything got cleaned up
+///
+/// Otherwise it stays for `recover` to remove what is left
+pub fn close() {
+    let Some((_, path)) = JOURNAL.lock().unwrap().take() else {
+        return;
+    };
+    let clean = std::fs::read_to_string(&path)
+        .map(|journal| parse_journal(&journal).1.is_empty())
+        .unwrap_or(false);
+    if clean {
+        if let Err(e) = std::fs::remove_file(&path) {
+            log::warn!("Failed to remove the journal: {e}");
+        }
+    }
+}
+
+#[test]
+fn test_parse_journal() {
+    let journal = "pid 42
++ replaced-root eth0 tbf 8001: rate 1Mbit burst 4Kb lat 400ms
++ ingress eth0
++ created-ifb eltrafico-ifb0
++ root eltrafico-ifb0
++ root eth0
+- root eltrafico-ifb0
+- created-ifb eltrafico-ifb0
++ activated-ifb ifb0
+";
+    assert_eq!(
+        parse_journal(journal),
+        (
+            Some(42),
+            vec![
+                Entry::ReplacedRootQDisc(RootQDiscSnapshot {
+                    device: "eth0".into(),
+                    kind: "tbf".into(),
+                    handle: crate::kernel::Handle::new(0x8001, 0),
+                    options: "rate 1Mbit burst 4Kb lat 400ms".into(),
+                }),
+                Entry::IngressQDisc("eth0".into()),
+                Entry::RootQDisc("eth0".into()),
+                Entry::ActivatedIfb("ifb0".into()),
+            ]
+        )
+    );
+    assert_eq!(parse_journal(""), (None, vec![]));
+}
@@ -0,0 +1,240 @@
+//! Typed view of the qdiscs, classes and filters of a device, read from `tc -j`
+use crate::Result;
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// tc handle, written in hex as `major:minor`
+#[derive(Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug, Default)]
+#[serde(try_from = "String")]
+pub struct Handle {
+    pub major: usize,
+    pub minor: usize,
+}
+
+impl Handle {
+    pub fn new(major: usize, minor: usize) -> Self {
+        Self { major, minor }
+    }
+}
+
+impl FromStr for Handle {
+    type Err = String;
+
+    fn from_str(handle: &str) -> std::result::Result<Self, Self::Err> {
+        let (major, minor) = handle
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid handle {handle}"))?;
+        let parse = |part: &str| -> std::result::Result<usize, String> {
+            if part.is_empty() {
+                return Ok(0);
+            }
+            usize::from_str_radix(part, 16).map_err(|e| format!("Invalid handle {handle}: {e}"))
+        };
+        Ok(Self {
+            major: parse(major)?,
+            minor: parse(minor)?,
+        })
+    }
+}
+
+impl TryFrom<String> for Handle {
+    type Error = String;
+
+    fn try_from(handle: String) -> std::result::Result<Self, Self::Error> {
+        handle.parse()
+    }
+}
+
+impl Serialize for Handle {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.minor == 0 {
+            write!(f, "{:x}:", self.major)
+        } else {
+            write!(f, "{:x}:{:x}", self.major, self.minor)
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct QDiscState {
+    pub kind: String,
+    pub handle: Handle,
+    #[serde(default)]
+    pub root: bool,
+    pub parent: Option<Handle>,
+    #[serde(default)]
+    pub options: serde_json::Value,
+}
+
+impl QDiscState {
+    /// Qdiscs installed by the kernel have no handle
+    pub fn is_default(&self) -> bool {
+        self.handle.major == 0
+    }
+
+    pub fn is_ingress(&self) -> bool {
+        self.kind == "ingress" || self.kind == "clsact"
+    }
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct ClassState {
+    #[serde(rename = "class")]
+    pub kind: String,
+    pub handle: Handle,
+    #[serde(default)]
+    pub root: bool,
+    pub parent: Option<Handle>,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct FilterState {
+    pub parent: Handle,
+    pub protocol: String,
+    pub pref: usize,
+    pub kind: String,
+    /// Filters without options are the heads of a filter chain
+    pub options: Option<FilterOptions>,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct FilterOptions {
+    /// u32 handles are `table:bucket:node`
+    pub fh: Option<String>,
+    pub flowid: Option<Handle>,
+    /// Hash table the filter sends the packets to
+    pub link: Option<Handle>,
+    #[serde(default)]
+    pub actions: Vec<serde_json::Value>,
+}
+
+fn tc_json(what: &str, device: &str) -> Result<String> {
+    let output = crate::utils::run(format!("tc -j {what} show dev {device}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to read the {what}s of {device}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// tc prints nothing instead of `[]` for some devices
+fn parse_list<T: for<'de> Deserialize<'de>>(json: &str) -> Result<Vec<T>> {
+    if json.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    Ok(serde_json::from_str(json)?)
+}
+
+pub fn qdiscs(device: &str) -> Result<Vec<QDiscState>> {
+    parse_list(&tc_json("qdisc", device)?)
+}
+
+pub fn classes(device: &str) -> Result<Vec<ClassState>> {
+    parse_classes(&tc_json("class", device)?)
+}
+
+pub fn filters(device: &str) -> Result<Vec<FilterState>> {
+    parse_list(&tc_json("filter", device)?)
+}
+
+/// Some qdiscs, htb included, print their classes as text even with `-j`:
+/// `class htb 1:2 parent 1:1 prio 0 rate 8bit ...`
+fn parse_classes(output: &str) -> Result<Vec<ClassState>> {
+    if output.trim_start().starts_with('[') {
+        return parse_list(output);
+    }
+    let mut classes = vec![];
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        let mut fields = line.split_whitespace();
+        let (Some("class"), Some(kind), Some(handle), Some(position)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(format!("Failed to parse class: {line}").into());
+        };
+        let parent = match position {
+            "parent" => Some(fields.next().unwrap_or_default().parse()?),
+            _ => None,
+        };
+        classes.push(ClassState {
+            kind: kind.to_string(),
+            handle: handle.parse()?,
+            root: position == "root",
+            parent,
+        });
+    }
+    Ok(classes)
+}
+
+#[test]
+fn test_handle() {
+    assert_eq!("a:1f".parse(), Ok(Handle::new(10, 31)));
+    assert_eq!("ffff:".parse(), Ok(Handle::new(0xffff, 0)));
+    assert_eq!(":1".parse(), Ok(Handle::new(0, 1)));
+    assert!("root".parse::<Handle>().is_err());
+    assert!("x:1".parse::<Handle>().is_err());
+    assert_eq!(Handle::new(10, 31).to_string(), "a:1f");
+    assert_eq!(Handle::new(1, 0).to_string(), "1:");
+}
+
+#[test]
+fn test_parse_qdiscs() {
+    let json = r#"[{"kind":"htb","handle":"a:","root":true,"refcnt":2,"options":{"r2q":10,"default":"0"}},{"kind":"ingress","handle":"ffff:","parent":"ffff:fff1","options":{}}]"#;
+    let qdiscs: Vec<QDiscState> = parse_list(json).unwrap();
+    assert_eq!(qdiscs.len(), 2);
+    assert_eq!(qdiscs[0].kind, "htb");
+    assert_eq!(qdiscs[0].handle, Handle::new(10, 0));
+    assert!(qdiscs[0].root && !qdiscs[0].is_default());
+    assert_eq!(qdiscs[1].parent, Some(Handle::new(0xffff, 0xfff1)));
+    assert!(qdiscs[1].is_ingress());
+    assert!(parse_list::<QDiscState>("").unwrap().is_empty());
+}
+
+#[test]
+fn test_parse_classes() {
+    let text = "class htb a:1 root rate 1Mbit ceil 1Mbit burst 1600b cburst 1600b
+class htb a:1f parent a:1 prio 0 rate 1Mbit ceil 1Mbit burst 1600b cburst 1600b
+";
+    let expected = vec![
+        ClassState {
+            kind: "htb".into(),
+            handle: Handle::new(10, 1),
+            root: true,
+            parent: None,
+        },
+        ClassState {
+            kind: "htb".into(),
+            handle: Handle::new(10, 31),
+            root: false,
+            parent: Some(Handle::new(10, 1)),
+        },
+    ];
+    assert_eq!(parse_classes(text).unwrap(), expected);
+    let json = r#"[{"class":"htb","handle":"a:1","root":true,"prio":0},{"class":"htb","handle":"a:1f","parent":"a:1","prio":0}]"#;
+    assert_eq!(parse_classes(json).unwrap(), expected);
+    assert!(parse_classes("").unwrap().is_empty());
+}
+
+#[test]
+fn test_parse_filters() {
+    let json = r#"[{"parent":"a:","protocol":"ip","pref":1,"kind":"u32","chain":0},{"parent":"a:","protocol":"ip","pref":1,"kind":"u32","chain":0,"options":{"fh":"1:bb:2","order":2,"key_ht":"1","bkt":"bb","flowid":"a:1","not_in_hw":true,"match":{"value":"1bb0000","mask":"ffff0000","offmask":"","off":20}}}]"#;
+    let filters: Vec<FilterState> = parse_list(json).unwrap();
+    assert_eq!(filters[0].options, None);
+    let options = filters[1].options.as_ref().unwrap();
+    assert_eq!(options.fh.as_deref(), Some("1:bb:2"));
+    assert_eq!(options.flowid, Some(Handle::new(10, 1)));
+    let json = r#"[{"parent":"a:","protocol":"ip","pref":1,"kind":"u32","chain":0,"options":{"fh":"801::800","order":2048,"key_ht":"801","bkt":"0","link":"1:","not_in_hw":true,"match":{"value":"0","mask":"0","offmask":"","off":0},"hash":{"mask":"ff0000","off":20}}}]"#;
+    let filters: Vec<FilterState> = parse_list(json).unwrap();
+    let options = filters[0].options.as_ref().unwrap();
+    assert_eq!(options.link, Some(Handle::new(1, 0)));
+    assert!(options.actions.is_empty());
+}
@@ -0,0 +1,465 @@
+//! Shape the traffic of programs with tc
+//!
+//! [`Shaper`] is the engine behind the `eltrafico-tc` binary, frontends written in Rust can embed it
+//! instead of speaking the stdin protocol. It needs the same privileges as the binary.
+mod ifb;
+pub mod ipc;
+pub mod journal;
+mod kernel;
+mod reconcile;
+mod shaper;
+mod tc;
+mod transaction;
+mod utils;
+
+pub use ipc::{LimitConfig, ProgramInfo};
+pub use kernel::Handle;
+pub use shaper::ProgramRule;
+pub use utils::{ifconfig, Interface, Kind, Status as InterfaceStatus};
+
+use ipc::{Dump, ProgramStatus, Status};
+use log::{info, trace, warn};
+use shaper::{ShapedInterface, ShapingState};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use utils::{default_route_interface, program_info, ss, watch_routes, Connection};
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Interface name that makes the shaping follow the default route
+pub const AUTO_INTERFACE: &str = "auto";
+
+/// How long a program can be gone before it exits and its classes are removed
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// What happened during a `poll`, displayed as a line of the stdout protocol
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Event {
+    /// A program was seen for the first time, or came back after it exited
+    ProgramEntry(ProgramInfo),
+    /// A program had no connections for the idle timeout
+    ProgramExit(String),
+    /// The shaping moved to the interface carrying the default route
+    InterfaceChanged(String),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::ProgramEntry(program) => write!(
+                f,
+                "ProgramEntry: {}",
+                serde_json::to_string(program).map_err(|_| fmt::Error)?
+            ),
+            Event::ProgramExit(program) => write!(f, "ProgramExit: {program}"),
+            Event::InterfaceChanged(interface) => write!(f, "InterfaceChanged: {interface}"),
+        }
+    }
+}
+
+impl Event {
+    /// The line for a frontend speaking `version` of the protocol
+    ///
+    /// Before version 2 `ProgramEntry` only had the name, frontends that never said `Hello`
+    /// still get that
+    pub fn line(&self, version: u32) -> String {
+        match self {
+            Event::ProgramEntry(program) if version < 2 => {
+                format!("ProgramEntry: {}", program.name)
+            }
+            event => event.to_string(),
+        }
+    }
+
+    /// Parse a line of the stdout protocol, `None` if it isn't an event
+    pub fn parse(line: &str) -> Option<Self> {
+        let (kind, name) = line.trim().split_once(": ")?;
+        if kind == "ProgramEntry" {
+            let program = serde_json::from_str(name).unwrap_or_else(|_| ProgramInfo {
+                name: name.to_string(),
+                ..Default::default()
+            });
+            return Some(Event::ProgramEntry(program));
+        }
+        let name = name.to_string();
+        match kind {
+            "ProgramExit" => Some(Event::ProgramExit(name)),
+            "InterfaceChanged" => Some(Event::InterfaceChanged(name)),
+            _ => None,
+        }
+    }
+}
+
+/// The shaping engine: the rules, the shaped interfaces and the programs seen so far
+///
+/// Changes are applied right away, `poll` has to be called regularly to follow the connections.
+/// The interfaces are cleaned up on `stop` or when the shaper is dropped.
+pub struct Shaper {
+    state: ShapingState,
+    interfaces: Vec<ShapedInterface>,
+    /// in auto mode the shaping moves to whatever interface carries the default route
+    auto_interface: bool,
+    route_changes: Option<mpsc::Receiver<()>>,
+    /// the default route changed but moving the shaping failed, `poll` tries again
+    route_pending: bool,
+    /// the connections of the last scan, the filters of their ports follow the rules
+    connections: HashMap<String, Vec<Connection>>,
+    /// when each program last had a connection
+    last_seen: HashMap<String, Instant>,
+    idle_timeout: Duration,
+    subscribers: Vec<mpsc::Sender<Event>>,
+}
+
+impl Default for Shaper {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDLE_TIMEOUT)
+    }
+}
+
+impl Shaper {
+    /// Programs without connections for `idle_timeout` exit and lose their classes
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            state: ShapingState::default(),
+            interfaces: vec![],
+            auto_interface: false,
+            route_changes: None,
+            route_pending: false,
+            connections: HashMap::new(),
+            last_seen: HashMap::new(),
+            idle_timeout,
+            subscribers: vec![],
+        }
+    }
+
+    /// A stream of the events that happen from now on
+    pub fn subscribe(&mut self) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    fn emit(&mut self, event: Event) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Shape only this interface, [`AUTO_INTERFACE`] follows the default route
+    pub fn set_interface(&mut self, name: &str) -> Result<()> {
+        if name == AUTO_INTERFACE {
+            self.auto_interface = true;
+            if self.route_changes.is_none() {
+                self.route_changes = Some(watch_routes()?);
+            }
+            let followed = self.follow_default_route();
+            self.route_pending = followed.is_err();
+            return followed;
+        }
+        self.auto_interface = false;
+        self.route_pending = false;
+        for interface in self.interfaces.drain(..) {
+            interface.clean_up()?;
+        }
+        self.add_interface(name)
+    }
+
+    /// Shape this interface too
+    pub fn add_interface(&mut self, name: &str) -> Result<()> {
+        if name == AUTO_INTERFACE {
+            return Err(format!("{AUTO_INTERFACE} can only be used with set_interface").into());
+        }
+        if self.interfaces.iter().any(|i| i.name == name) {
+            warn!("{name} is already shaped");
+            return Ok(());
+        }
+        let used_ifb_devices: Vec<String> = self
+            .interfaces
+            .iter()
+            .map(|i| i.ingress.device.clone())
+            .collect();
+        self.interfaces.push(ShapedInterface::setup(
+            name,
+            &used_ifb_devices,
+            &self.state,
+        )?);
+        Ok(())
+    }
+
+    pub fn remove_interface(&mut self, name: &str) -> Result<()> {
+        if let Some(pos) = self.interfaces.iter().position(|i| i.name == name) {
+            self.interfaces.remove(pos).clean_up()?;
+        }
+        Ok(())
+    }
+
+    /// Move the whole shaping setup to the interface carrying the default route
+    fn follow_default_route(&mut self) -> Result<()> {
+        let Some(default_interface) = default_route_interface()? else {
+            warn!("there is no default route to follow");
+            return Ok(());
+        };
+        if self.interfaces.len() == 1 && self.interfaces[0].name == default_interface {
+            return Ok(());
+        }
+
+        info!("following the default route to {default_interface}");
+        for interface in self.interfaces.drain(..) {
+            interface.clean_up()?;
+        }
+        self.add_interface(&default_interface)?;
+        self.emit(Event::InterfaceChanged(default_interface));
+        Ok(())
+    }
+
+    /// Switch to the updated state if every interface accepts it
+    ///
+    /// A rejected change is rolled back and the shaping goes on with the current state,
+    /// interfaces that already took the change go back to it on the next `poll`
+    fn update_state(&mut self, update: impl FnOnce(&mut ShapingState)) -> Result<()> {
+        let mut new_state = self.state.clone();
+        update(&mut new_state);
+        for interface in &mut self.interfaces {
+            interface.reconcile(&new_state, &self.connections)?;
+        }
+        self.state = new_state;
+        Ok(())
+    }
+
+    pub fn set_global(&mut self, config: LimitConfig) -> Result<()> {
+        self.update_state(|state| state.global_limit = config)
+    }
+
+    /// Limit the program on every shaped interface
+    pub fn set_program(&mut self, name: &str, config: LimitConfig) -> Result<()> {
+        self.set_program_rule(
+            name,
+            ProgramRule::Limit {
+                config,
+                interfaces: None,
+            },
+        )
+    }
+
+    pub fn set_program_rule(&mut self, name: &str, rule: ProgramRule) -> Result<()> {
+        self.update_state(|state| {
+            state.program_rules.insert(name.to_string(), rule);
+        })
+    }
+
+    /// Drop all of the traffic of the program
+    pub fn block(&mut self, name: &str) -> Result<()> {
+        self.set_program_rule(name, ProgramRule::Block)
+    }
+
+    /// In allowlist mode only the traffic of allowed programs passes
+    pub fn set_allowlist(&mut self, on: bool) -> Result<()> {
+        self.update_state(|state| state.allowlist = on)
+    }
+
+    pub fn allow(&mut self, name: &str) -> Result<()> {
+        self.update_state(|state| {
+            state.allowed_programs.insert(name.to_string());
+        })
+    }
+
+    pub fn disallow(&mut self, name: &str) -> Result<()> {
+        self.update_state(|state| {
+            state.allowed_programs.remove(name);
+        })
+    }
+
+    /// Scan the connections, announce new and exited programs and update the shaping
+    pub fn poll(&mut self) -> Result<()> {
+        // move the shaping if the default route changed
+        let routes_changed = self
+            .route_changes
+            .as_ref()
+            .is_some_and(|route_changes| route_changes.try_iter().count() > 0);
+        if self.auto_interface && (routes_changed || self.route_pending) {
+            // the route changes are drained, remember to try again
+            match self.follow_default_route() {
+                Ok(()) => self.route_pending = false,
+                Err(e) => {
+                    warn!("Failed to follow the default route, trying again on the next poll: {e}");
+                    self.route_pending = true;
+                }
+            }
+        }
+
+        // look for new programs, the shaping stays as it is until a scan succeeds
+        self.connections = match ss() {
+            Ok(connections) => connections,
+            Err(e) => {
+                warn!("Failed to scan the connections: {e}");
+                return Ok(());
+            }
+        };
+        let mut events = vec![];
+        for program in self.connections.keys() {
+            if !self.state.program_rules.contains_key(program) {
+                trace!("detected a new program {program}");
+                // new programs get a placeholder rule
+                self.state
+                    .program_rules
+                    .insert(program.clone(), ProgramRule::default());
+                events.push(Event::ProgramEntry(program_info(
+                    program,
+                    &self.connections[program],
+                )));
+            }
+        }
+
+        // programs that are gone for a while exit and don't keep their classes
+        let now = Instant::now();
+        for program in self.connections.keys() {
+            self.last_seen.insert(program.clone(), now);
+        }
+        let idle_programs: HashSet<String> = self
+            .last_seen
+            .iter()
+            .filter(|(_, seen)| now.duration_since(**seen) >= self.idle_timeout)
+            .map(|(program, _)| program.clone())
+            .collect();
+        for program in idle_programs.difference(&self.state.idle_programs) {
+            trace!("{program} exited");
+            events.push(Event::ProgramExit(program.clone()));
+        }
+        // the program came back, announce it again
+        for program in self.state.idle_programs.difference(&idle_programs) {
+            let connections = self.connections.get(program).map_or(&[][..], Vec::as_slice);
+            events.push(Event::ProgramEntry(program_info(program, connections)));
+        }
+        self.state.idle_programs = idle_programs;
+        for event in events {
+            self.emit(event);
+        }
+
+        // bring the kernel in line with the rules and the connections,
+        // a failed interface is tried again on the next poll
+        for interface in &mut self.interfaces {
+            if let Err(e) = interface.reconcile(&self.state, &self.connections) {
+                warn!("Failed to update the shaping of {}: {e}", interface.name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            interfaces: self
+                .interfaces
+                .iter()
+                .map(ShapedInterface::status)
+                .collect(),
+            auto_interface: self.auto_interface,
+            global_limit: self.state.global_limit.clone(),
+            allowlist: self.state.allowlist,
+        }
+    }
+
+    /// Every program seen so far with its rule, sorted by name
+    pub fn programs(&self) -> Vec<ProgramStatus> {
+        let mut programs: Vec<ProgramStatus> = self
+            .state
+            .program_rules
+            .iter()
+            .map(|(name, rule)| ProgramStatus {
+                name: name.clone(),
+                rule: rule.clone(),
+                allowed: self.state.allowed_programs.contains(name),
+                idle: self.state.idle_programs.contains(name),
+            })
+            .collect();
+        programs.sort_by(|a, b| a.name.cmp(&b.name));
+        programs
+    }
+
+    /// What is actually applied in the kernel
+    pub fn dump(&self) -> Result<Dump> {
+        Ok(Dump {
+            status: self.status(),
+            programs: self.programs(),
+            interfaces: self
+                .interfaces
+                .iter()
+                .map(|interface| interface.dump(&self.connections))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Clean up every shaped interface
+    pub fn stop(&mut self) -> Result<()> {
+        for interface in self.interfaces.drain(..) {
+            interface.clean_up()?;
+        }
+        Ok(())
+    }
+
+    /// Keep the shaping of every interface after the shaper is gone
+    ///
+    /// Nothing follows the connections anymore, only the global limit stays meaningful
+    pub fn detach(mut self) {
+        for interface in self.interfaces.drain(..) {
+            interface.detach();
+        }
+    }
+}
+
+#[test]
+fn test_event_lines() {
+    let firefox = ProgramInfo {
+        name: "firefox".into(),
+        pids: vec![1996],
+        exe: Some("/usr/lib/firefox/firefox".into()),
+        cmdline: None,
+    };
+    assert_eq!(
+        Event::ProgramEntry(firefox.clone()).to_string(),
+        r#"ProgramEntry: {"name":"firefox","pids":[1996],"exe":"/usr/lib/firefox/firefox","cmdline":null}"#
+    );
+    assert_eq!(
+        Event::parse(&Event::ProgramEntry(firefox.clone()).to_string()),
+        Some(Event::ProgramEntry(firefox.clone()))
+    );
+    assert_eq!(
+        Event::ProgramEntry(firefox).line(1),
+        "ProgramEntry: firefox"
+    );
+    assert_eq!(
+        Event::parse("ProgramEntry: Web Content"),
+        Some(Event::ProgramEntry(ProgramInfo {
+            name: "Web Content".into(),
+            ..Default::default()
+        }))
+    );
+    assert_eq!(
+        Event::ProgramExit("firefox".into()).to_string(),
+        "ProgramExit: firefox"
+    );
+    assert_eq!(
+        Event::InterfaceChanged("wlan0".into()).to_string(),
+        "InterfaceChanged: wlan0"
+    );
+    assert_eq!(
+        Event::parse("ProgramExit: firefox\n"),
+        Some(Event::ProgramExit("firefox".into()))
+    );
+    assert_eq!(Event::parse("Status: {}"), None);
+    assert_eq!(Event::parse("Stop"), None);
+}
+
+#[test]
+fn test_subscribe() {
+    let mut shaper = Shaper::default();
+    let events = shaper.subscribe();
+    let dropped = shaper.subscribe();
+    drop(dropped);
+    shaper.emit(Event::ProgramExit("firefox".into()));
+    assert_eq!(
+        events.try_iter().collect::<Vec<_>>(),
+        vec![Event::ProgramExit("firefox".into())]
+    );
+    assert_eq!(shaper.subscribers.len(), 1);
+}
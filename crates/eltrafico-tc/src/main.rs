@@ -1,21 +1,16 @@
-mod tc;
-mod utils;
-use crate::ipc::LimitConfig;
-use crate::tc::{
-    build_global_rate_commands, tc_add_htb_class, tc_add_u32_filter, tc_remove_qdisc,
-    tc_remove_u32_filter, tc_setup, QDisc, INGRESS_QDISC_PARENT_ID,
+use eltrafico_tc::ipc::{Message, PROTOCOL_VERSION, SOCKET_PATH};
+use eltrafico_tc::{
+    journal, Event, LimitConfig, ProgramRule, Result, Shaper, AUTO_INTERFACE, DEFAULT_IDLE_TIMEOUT,
 };
-use crate::utils::ss;
 use log::{info, trace, warn};
 use simple_logger::SimpleLogger;
 use std::collections::HashMap;
-use std::io::{self, Write};
-use std::sync::mpsc;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
-mod ipc;
-use ipc::Message;
-
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 fn main() -> Result<()> {
     SimpleLogger::new()
@@ -25,69 +20,237 @@ fn main() -> Result<()> {
 
     let args: Vec<String> = std::env::args().collect();
     if args.contains(&"-h".to_string()) || args.contains(&"--help".to_string()) {
-        //TODO helpful message
+        println!("{USAGE}");
         std::process::exit(0);
     }
-    limit(Some(Duration::from_secs(1)), io::stdout(), io::stdin())
+    if args.contains(&"--cleanup".to_string()) {
+        return journal::recover();
+    }
+    match args.get(1).map(String::as_str) {
+        Some("global") => return global_cap(&args),
+        Some("clear") => return clear_global_cap(&args),
+        _ => {}
+    }
+
+    let idle_timeout = parse_idle_timeout(&args)?;
+    let socket = args.contains(&"--socket".to_string());
+    let socket_group = option_value(&args, "--socket-group");
+    if socket_group.is_some() && !socket {
+        return Err("--socket-group only makes sense with --socket".into());
+    }
+
+    // a crashed run can leave the network shaped, clean that up before shaping again
+    journal::open()?;
+    let frontends = Frontends::default();
+    let (tx_requests, rx_requests) = mpsc::channel();
+    // every way of quitting goes through the Stop message, so the cleanup happens in one place
+    handle_ctrlc(tx_requests.clone());
+    let result = if socket {
+        listen(
+            SOCKET_PATH,
+            socket_group.map(String::as_str),
+            tx_requests,
+            frontends.clone(),
+        )
+    } else {
+        frontends.add(STDOUT, Box::new(io::stdout()));
+        read_stdin(tx_requests);
+        Ok(())
+    }
+    .and_then(|_| {
+        limit(
+            Some(Duration::from_secs(1)),
+            idle_timeout,
+            &frontends,
+            rx_requests,
+        )
+    });
+    if socket {
+        let _ = std::fs::remove_file(SOCKET_PATH);
+    }
+    journal::close();
+    result
 }
 
-pub fn limit(delay: Option<Duration>, mut stdout: io::Stdout, stdin: io::Stdin) -> Result<()> {
-    // block till we get an initial interface
-    // and while we're at it if we get a global limit msg save the values
-    // also if we get stop msg quit early
-    let mut global_limit = Default::default();
+const USAGE: &str = "Usage: eltrafico-tc [--cleanup] [--idle-timeout SECS] [--socket]
+       eltrafico-tc global --interface NAME [--down RATE] [--up RATE] [--foreground]
+       eltrafico-tc clear --interface NAME
+
+Shape the traffic of programs, controlled with messages on stdin.
+global caps all of the traffic of the interface and exits, the cap stays till clear removes it.
+
+Options:
+    --cleanup            Remove what a crashed run left behind and exit
+    --idle-timeout SECS  Programs without connections for that long exit and lose their
+                         classes [default: 300]
+    --down, --up RATE    With global, the download and upload caps, like 20mbit
+    --foreground         With global, stay till Ctrl-C and remove the cap then
+    --socket             Take the messages from the frontends connected to
+                         /run/eltrafico-tc.sock instead of stdin, the shaping goes on when
+                         they disconnect
+    --socket-group GROUP With --socket, give the socket to this group so its members can run
+                         eltrafico-ctl and the frontends without root, only root can otherwise
+    -h, --help           Print this help";
+
+/// The value following the option
+fn option_value<'a>(args: &'a [String], option: &str) -> Option<&'a String> {
+    let pos = args.iter().position(|arg| arg == option)?;
+    args.get(pos + 1)
+}
 
-    trace!("waiting for interface");
-    let mut current_interface = {
-        let mut msg = String::new();
-        loop {
-            if stdin.read_line(&mut msg).is_err() {
-                return Ok(());
-            }
-            trace!("recieved message: {}", msg.trim());
-            match msg.clone().try_into() {
-                Ok(msg) => match msg {
-                    Message::Stop => {
-                        writeln!(stdout, "Stop")?;
-                        return Ok(());
-                    }
-                    Message::Interface(name) => break name,
-                    Message::Global { config } => {
-                        global_limit = config;
-                    }
-                    Message::Program { .. } => (),
-                },
-                Err(e) => warn!("{e}"),
-            }
-            msg.clear();
-        }
+fn parse_interface(args: &[String]) -> Result<&String> {
+    Ok(option_value(args, "--interface").ok_or("--interface expects an interface name")?)
+}
+
+/// Cap the interface without a frontend, like wondershaper
+fn global_cap(args: &[String]) -> Result<()> {
+    let interface = parse_interface(args)?;
+    let config = LimitConfig {
+        download_rate: option_value(args, "--down").cloned(),
+        upload_rate: option_value(args, "--up").cloned(),
+        ..Default::default()
     };
-    trace!("selected interface is {current_interface}");
-
-    trace!("running tc_setup");
-    let (mut root_ingress, mut root_egress) = {
-        let global_limit = global_limit.clone();
-        tc_setup(
-            current_interface.clone(),
-            global_limit.download_rate,
-            global_limit.download_minimum_rate,
-            global_limit.upload_rate,
-            global_limit.upload_minimum_rate,
-            global_limit.download_priority,
-            global_limit.upload_priority,
-        )?
+    let foreground = args.contains(&"--foreground".to_string());
+
+    // a cap that is already there gets replaced
+    journal::open_at(&journal::global_cap_path(interface))?;
+    let mut shaper = Shaper::default();
+    let result = shaper
+        .set_global(config)
+        .and_then(|_| shaper.set_interface(interface));
+    if result.is_err() || !foreground {
+        if result.is_ok() {
+            shaper.detach();
+            info!("{interface} is capped, eltrafico-tc clear --interface {interface} removes it");
+        }
+        journal::close();
+        return result;
+    }
+
+    let (tx, rx) = mpsc::channel();
+    handle_ctrlc(tx);
+    let _ = rx.recv();
+    let result = shaper.stop();
+    journal::close();
+    result
+}
+
+fn clear_global_cap(args: &[String]) -> Result<()> {
+    let interface = parse_interface(args)?;
+    let path = journal::global_cap_path(interface);
+    if !std::path::Path::new(&path).exists() {
+        return Err(format!("{interface} isn't capped by eltrafico-tc global").into());
+    }
+    journal::recover_at(&path)
+}
+
+fn parse_idle_timeout(args: &[String]) -> Result<Duration> {
+    let Some(pos) = args.iter().position(|arg| arg == "--idle-timeout") else {
+        return Ok(DEFAULT_IDLE_TIMEOUT);
     };
+    let secs = args
+        .get(pos + 1)
+        .and_then(|secs| secs.parse().ok())
+        .ok_or("--idle-timeout expects a number of seconds")?;
+    Ok(Duration::from_secs(secs))
+}
+
+/// The frontend reading our stdout
+const STDOUT: usize = 0;
+
+/// A line from a frontend, the replies go back to that frontend
+struct Request {
+    line: String,
+    frontend: usize,
+}
+
+/// The protocol version of frontends that never said `Hello`
+const FIRST_VERSION: u32 = 1;
+
+struct Frontend {
+    output: Box<dyn Write + Send>,
+    /// the events are written the way this version of the protocol expects
+    version: u32,
+}
 
-    handle_ctrlc(root_ingress.clone(), current_interface.clone());
+/// Where the replies and the events go, stdout or the clients of the socket
+#[derive(Clone, Default)]
+struct Frontends(Arc<Mutex<HashMap<usize, Frontend>>>);
+
+impl Frontends {
+    fn add(&self, id: usize, output: Box<dyn Write + Send>) {
+        self.0.lock().unwrap().insert(
+            id,
+            Frontend {
+                output,
+                version: FIRST_VERSION,
+            },
+        );
+    }
+
+    fn set_version(&self, id: usize, version: u32) {
+        if let Some(frontend) = self.0.lock().unwrap().get_mut(&id) {
+            frontend.version = version;
+        }
+    }
+
+    fn remove(&self, id: usize) {
+        self.0.lock().unwrap().remove(&id);
+    }
+
+    /// Write a line to one frontend, a frontend that can't be written to is dropped
+    fn send(&self, id: usize, line: &str) {
+        let mut frontends = self.0.lock().unwrap();
+        if let Some(Frontend { output, .. }) = frontends.get_mut(&id) {
+            if let Err(e) = writeln!(output, "{line}").and_then(|_| output.flush()) {
+                warn!("Failed to write to frontend {id}: {e}");
+                frontends.remove(&id);
+            }
+        }
+    }
 
-    let (tx_stdin, rx_stdin) = mpsc::channel();
+    fn broadcast(&self, line: &str) {
+        let ids: Vec<usize> = self.0.lock().unwrap().keys().copied().collect();
+        for id in ids {
+            self.send(id, line);
+        }
+    }
 
+    /// Write the event to every frontend in the form its protocol version expects
+    fn broadcast_event(&self, event: &Event) {
+        let versions: Vec<(usize, u32)> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, frontend)| (*id, frontend.version))
+            .collect();
+        for (id, version) in versions {
+            self.send(id, &event.line(version));
+        }
+    }
+}
+
+fn read_stdin(tx_requests: mpsc::Sender<Request>) {
     std::thread::spawn(move || {
+        let stdin = io::stdin();
         let mut input = String::new();
         loop {
             match stdin.read_line(&mut input) {
+                // the frontend is gone
+                Ok(0) => {
+                    let _ = tx_requests.send(Request {
+                        line: "Stop".to_string(),
+                        frontend: STDOUT,
+                    });
+                    break;
+                }
                 Ok(_) => {
-                    if tx_stdin.send(input.clone()).is_err() {
+                    let request = Request {
+                        line: input.clone(),
+                        frontend: STDOUT,
+                    };
+                    if tx_requests.send(request).is_err() {
                         break;
                     }
                 }
@@ -96,179 +259,115 @@ pub fn limit(delay: Option<Duration>, mut stdout: io::Stdout, stdin: io::Stdin)
             input.clear();
         }
     });
+}
 
-    let mut program_to_trafficid_map = HashMap::new();
-    let mut filtered_ports: HashMap<DirPort, String> = HashMap::new();
-    let mut program_to_ports: HashMap<String, Vec<DirPort>> = HashMap::new();
-
-    loop {
-        // check for new user limits
-        // and add htb class for them
-
-        // check if we received a new msg on stdin
-        if let Ok(msg) = rx_stdin.try_recv() {
-            match Message::try_from(msg) {
-                Ok(msg) => match msg {
-                    Message::Interface(name) => {
-                        info!("recieved interface: {name}");
-                        clean_up(&root_ingress.device, &current_interface)?;
-
-                        current_interface = name;
-                        resetup_tc_and_filtered_ports(
-                            &current_interface,
-                            &mut root_ingress,
-                            &mut root_egress,
-                            global_limit.clone(),
-                            &mut filtered_ports,
-                            &mut program_to_ports,
-                        )?;
-                    }
-                    Message::Global { config } => {
-                        info!("recieved global limit: {config:?}");
-                        global_limit = config;
-                        for cmd in
-                            build_global_rate_commands(&root_ingress, &root_egress, &global_limit)
-                        {
-                            run!("{cmd}")?;
-                        }
-                    }
-                    Message::Program { name, config } => {
-                        info!("recieved program: {name} {config:?}");
-                        let LimitConfig {
-                            download_rate,
-                            download_minimum_rate,
-                            upload_rate,
-                            upload_minimum_rate,
-                            download_priority,
-                            upload_priority,
-                        } = config;
-
-                        for (port, filter_id) in remove_old_program_filters(
-                            &mut program_to_ports,
-                            &name,
-                            &mut filtered_ports,
-                        ) {
-                            match port {
-                                DirPort::Ingress(_) => {
-                                    tc_remove_u32_filter(&root_ingress, filter_id)?
-                                }
-                                DirPort::Egress(_) => {
-                                    tc_remove_u32_filter(&root_egress, filter_id)?
-                                }
-                            }
-                        }
-
-                        let ingress_class_id = if let Some(download_rate) = download_rate {
-                            Some(tc_add_htb_class(
-                                &root_ingress,
-                                Some(download_rate),
-                                download_minimum_rate,
-                                download_priority,
-                            )?)
-                        } else {
-                            None
-                        };
-
-                        let egress_class_id = if let Some(upload_rate) = upload_rate {
-                            Some(tc_add_htb_class(
-                                &root_egress,
-                                Some(upload_rate),
-                                upload_minimum_rate,
-                                upload_priority,
-                            )?)
-                        } else {
-                            None
-                        };
-
-                        program_to_trafficid_map
-                            .insert(name.clone(), (ingress_class_id, egress_class_id));
-                    }
-                    Message::Stop => {
-                        info!("recieved Stop");
-                        clean_up(&root_ingress.device, &current_interface)?;
-                        writeln!(stdout, "Stop")?;
-                        break Ok(());
-                    }
-                },
-                Err(e) => log::warn!("{e}"),
-            }
+/// Accept frontends on a unix socket, unlike stdin a frontend leaving doesn't stop the shaping
+///
+/// The socket is read-write for its owner and its group, root unless `group` is given
+fn listen(
+    path: &str,
+    group: Option<&str>,
+    tx_requests: mpsc::Sender<Request>,
+    frontends: Frontends,
+) -> Result<()> {
+    // the journal is open, so a socket left there belongs to a run that is gone
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))?;
+    if let Some(group) = group {
+        let output = std::process::Command::new("chgrp")
+            .args([group, path])
+            .output()?;
+        if !output.status.success() {
+            let _ = std::fs::remove_file(path);
+            return Err(format!(
+                "Failed to give {path} to the group {group}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
         }
+    }
+    info!("listening on {path}");
 
-        // look for new ports to filter
-        let mut active_ports = HashMap::new();
-        for (program, connections) in ss()? {
-            let program_in_map = program_to_trafficid_map
-                .get(&program)
-                .map(ToOwned::to_owned);
-            let (ingress_class_id, egress_class_id) = match program_in_map {
-                Some(id) => id,
-                None => {
-                    trace!("detected a new program {program}");
-                    // this is a new program
-                    // add a placeholder for it in the program_to_trafficid_map
-                    // and send it to the gui
-                    program_to_trafficid_map.insert(program.clone(), (None, None));
-                    writeln!(stdout, "ProgramEntry: {program}")?;
+    std::thread::spawn(move || {
+        for (id, stream) in (STDOUT + 1..).zip(listener.incoming()) {
+            let stream = match stream.and_then(|s| Ok((s.try_clone()?, s))) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept a frontend: {e}");
                     continue;
                 }
             };
-
-            // filter the connection ports according the user specified limits
-            for connection in connections {
-                if let Some(ingress_class_id) = ingress_class_id {
-                    let ingress_port = DirPort::Ingress(connection.lport);
-
-                    if filtered_ports.contains_key(&ingress_port) {
-                        active_ports.insert(ingress_port, filtered_ports[&ingress_port].clone());
-                    } else {
-                        trace!(
-                            "adding a new ingress filter for port {} of connection {connection:?}",
-                            connection.lport
-                        );
-                        let ingress_filter_id =
-                            add_ingress_filter(connection.lport, &root_ingress, ingress_class_id)?;
-                        record_program_port(&mut program_to_ports, &program, ingress_port);
-                        active_ports.insert(ingress_port, ingress_filter_id);
+            trace!("frontend {id} connected");
+            let (reader, writer) = stream;
+            frontends.add(id, Box::new(writer));
+            let tx_requests = tx_requests.clone();
+            let frontends = frontends.clone();
+            std::thread::spawn(move || {
+                for line in BufReader::new(reader).lines() {
+                    let Ok(line) = line else {
+                        break;
+                    };
+                    if tx_requests.send(Request { line, frontend: id }).is_err() {
+                        return;
                     }
                 }
+                trace!("frontend {id} disconnected");
+                frontends.remove(id);
+            });
+        }
+    });
+    Ok(())
+}
 
-                if let Some(egress_class_id) = egress_class_id {
-                    let egress_port = DirPort::Egress(connection.lport);
-
-                    if filtered_ports.contains_key(&egress_port) {
-                        active_ports.insert(egress_port, filtered_ports[&egress_port].clone());
-                    } else {
-                        trace!(
-                            "adding a new egress filter for port {} of connection {connection:?}",
-                            connection.lport
-                        );
-                        let egress_filter_id =
-                            add_egress_filter(connection.lport, &root_egress, egress_class_id)?;
-                        record_program_port(&mut program_to_ports, &program, egress_port);
-                        active_ports.insert(egress_port, egress_filter_id);
-                    }
+fn limit(
+    delay: Option<Duration>,
+    idle_timeout: Duration,
+    frontends: &Frontends,
+    requests: mpsc::Receiver<Request>,
+) -> Result<()> {
+    let mut shaper = Shaper::new(idle_timeout);
+    let events = shaper.subscribe();
+
+    // block till we get an initial interface, the messages before it only set up the rules
+    trace!("waiting for interface");
+    loop {
+        let Ok(request) = requests.recv() else {
+            return Ok(());
+        };
+        trace!("recieved message: {}", request.line.trim());
+        match Message::try_from(request.line) {
+            Ok(msg) => {
+                let interface = matches!(msg, Message::Interface(_) | Message::AddInterface(_));
+                if !handle_message(&mut shaper, msg, frontends, request.frontend)? {
+                    return Ok(());
+                }
+                if interface {
+                    break;
                 }
             }
+            Err(e) => warn!("{e}"),
         }
+    }
 
-        // remove filter for freed ports
-        for (port, filter_id) in filtered_ports {
-            if !active_ports.contains_key(&port) {
-                match port {
-                    DirPort::Ingress(_) => {
-                        trace!("removing freed ingress port {port:?}");
-                        tc_remove_u32_filter(&root_ingress, filter_id)?;
-                    }
-                    DirPort::Egress(_) => {
-                        trace!("removing freed egress port {port:?}");
-                        tc_remove_u32_filter(&root_egress, filter_id)?;
+    loop {
+        // handle the messages that came in since the last scan
+        for request in requests.try_iter() {
+            match Message::try_from(request.line) {
+                Ok(msg) => {
+                    if !handle_message(&mut shaper, msg, frontends, request.frontend)? {
+                        return Ok(());
                     }
                 }
+                Err(e) => log::warn!("{e}"),
             }
         }
 
-        // update the currently filtered ports
-        filtered_ports = active_ports;
+        shaper.poll()?;
+        // send the new programs and the interface changes to the frontends
+        for event in events.try_iter() {
+            frontends.broadcast_event(&event);
+        }
 
         // delay scanning for active connections
         if let Some(delay) = delay {
@@ -277,161 +376,124 @@ pub fn limit(delay: Option<Duration>, mut stdout: io::Stdout, stdin: io::Stdin)
     }
 }
 
-/// Port with direction
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
-enum DirPort {
-    /// Incomming traffic
-    Ingress(usize),
-    /// Outgoing traffic
-    Egress(usize),
-}
-
-fn resetup_tc_and_filtered_ports(
-    current_interface: &str,
-    ingress: &mut QDisc,
-    egress: &mut QDisc,
-    global_limit: LimitConfig,
-    filtered_ports: &mut HashMap<DirPort, String>,
-    program_to_ports: &mut HashMap<String, Vec<DirPort>>,
-) -> Result<()> {
-    filtered_ports.clear();
-    program_to_ports.clear();
-
-    (*ingress, *egress) = tc_setup(
-        current_interface.to_string(),
-        global_limit.download_rate,
-        global_limit.download_minimum_rate,
-        global_limit.upload_rate,
-        global_limit.upload_minimum_rate,
-        global_limit.download_priority,
-        global_limit.upload_priority,
-    )?;
-
-    Ok(())
+/// Apply a message from the frontend, returns false once the shaping is stopped
+///
+/// Failing to shape an interface is fatal, a rejected rule is rolled back and only logged
+fn handle_message(
+    shaper: &mut Shaper,
+    msg: Message,
+    frontends: &Frontends,
+    frontend: usize,
+) -> Result<bool> {
+    match msg {
+        Message::Hello(version) => {
+            info!("recieved hello from a frontend speaking version {version}");
+            if version != PROTOCOL_VERSION {
+                warn!("the frontend speaks version {version}, we speak {PROTOCOL_VERSION}");
+            }
+            frontends.set_version(frontend, version.min(PROTOCOL_VERSION));
+            frontends.send(frontend, &Message::Hello(PROTOCOL_VERSION).to_string());
+        }
+        Message::Interface(name) => {
+            info!("recieved interface: {name}");
+            shaper.set_interface(&name)?;
+        }
+        Message::AddInterface(name) if name == AUTO_INTERFACE => {
+            warn!("{AUTO_INTERFACE} can only be used with Interface");
+        }
+        Message::AddInterface(name) => {
+            info!("recieved add interface: {name}");
+            shaper.add_interface(&name)?;
+        }
+        Message::RemoveInterface(name) => {
+            info!("recieved remove interface: {name}");
+            shaper.remove_interface(&name)?;
+        }
+        Message::Global { config } => {
+            info!("recieved global limit: {config:?}");
+            if let Err(e) = shaper.set_global(config) {
+                warn!("Failed to set the global limit: {e}");
+            }
+        }
+        Message::Program {
+            name,
+            config,
+            interfaces,
+        } => {
+            info!("recieved program: {name} {config:?} {interfaces:?}");
+            let rule = ProgramRule::Limit { config, interfaces };
+            if let Err(e) = shaper.set_program_rule(&name, rule) {
+                warn!("Failed to set the rule of {name}: {e}");
+            }
+        }
+        Message::Block { name } => {
+            info!("recieved block: {name}");
+            if let Err(e) = shaper.block(&name) {
+                warn!("Failed to set the rule of {name}: {e}");
+            }
+        }
+        Message::Allowlist(on) => {
+            info!("recieved allowlist: {on}");
+            if let Err(e) = shaper.set_allowlist(on) {
+                warn!("Failed to set the allowlist mode: {e}");
+            }
+        }
+        Message::Allow { name } => {
+            info!("recieved allow: {name}");
+            if let Err(e) = shaper.allow(&name) {
+                warn!("Failed to allow {name}: {e}");
+            }
+        }
+        Message::Disallow { name } => {
+            info!("recieved disallow: {name}");
+            if let Err(e) = shaper.disallow(&name) {
+                warn!("Failed to disallow {name}: {e}");
+            }
+        }
+        query @ (Message::Status | Message::ListPrograms | Message::Dump) => {
+            trace!("recieved query: {query:?}");
+            if let Err(e) = reply(shaper, &query, frontends, frontend) {
+                warn!("Failed to answer {query:?}: {e}");
+            }
+        }
+        Message::Stop => {
+            info!("recieved Stop");
+            shaper.stop()?;
+            frontends.broadcast("Stop");
+            return Ok(false);
+        }
+    }
+    Ok(true)
 }
 
-fn clean_up(ingress_device: &str, egress_device: &str) -> Result<()> {
-    log::info!("Cleaning up QDiscs");
-    tc_remove_qdisc(ingress_device.into(), None)?;
-    tc_remove_qdisc(egress_device.into(), None)?;
-    tc_remove_qdisc(egress_device.into(), Some(INGRESS_QDISC_PARENT_ID.into()))?;
+/// Answer a query with a line of json
+fn reply(shaper: &Shaper, query: &Message, frontends: &Frontends, frontend: usize) -> Result<()> {
+    let line = match query {
+        Message::Status => format!("Status: {}", serde_json::to_string(&shaper.status())?),
+        Message::ListPrograms => {
+            format!("Programs: {}", serde_json::to_string(&shaper.programs())?)
+        }
+        Message::Dump => format!("Dump: {}", serde_json::to_string(&shaper.dump()?)?),
+        _ => return Ok(()),
+    };
+    frontends.send(frontend, &line);
     Ok(())
 }
 
-fn add_ingress_filter(port: usize, ingress_qdisc: &QDisc, class_id: usize) -> Result<String> {
-    let filter_id = tc_add_u32_filter(
-        ingress_qdisc,
-        format!("match ip dport {port} 0xffff"),
-        class_id,
-    )?;
-    Ok(filter_id)
-}
-
-fn add_egress_filter(port: usize, egress_qdisc: &QDisc, class_id: usize) -> Result<String> {
-    let filter_id = tc_add_u32_filter(
-        egress_qdisc,
-        format!("match ip sport {port} 0xffff"),
-        class_id,
-    )?;
-    Ok(filter_id)
-}
-
-fn remove_old_program_filters(
-    program_to_ports: &mut HashMap<String, Vec<DirPort>>,
-    name: &str,
-    filtered_ports: &mut HashMap<DirPort, String>,
-) -> Vec<(DirPort, String)> {
-    program_to_ports
-        .remove(name)
-        .unwrap_or_default()
-        .into_iter()
-        .filter_map(|port| filtered_ports.remove(&port).map(|fid| (port, fid)))
-        .collect()
-}
-
-fn record_program_port(
-    program_to_ports: &mut HashMap<String, Vec<DirPort>>,
-    name: &str,
-    port: DirPort,
-) {
-    program_to_ports
-        .entry(name.to_string())
-        .or_default()
-        .push(port);
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn remove_old_program_filters_removes_ports_and_returns_filter_ids() {
-        let mut program_to_ports = HashMap::new();
-        program_to_ports.insert("test".into(), vec![DirPort::Ingress(1234)]);
-        let mut filtered_ports = HashMap::new();
-        filtered_ports.insert(DirPort::Ingress(1234), "filter:1".into());
-
-        let removed =
-            remove_old_program_filters(&mut program_to_ports, "test", &mut filtered_ports);
-
-        assert_eq!(removed, vec![(DirPort::Ingress(1234), "filter:1".into())]);
-        assert!(!filtered_ports.contains_key(&DirPort::Ingress(1234)));
-        assert!(!program_to_ports.contains_key("test"));
-    }
-
-    #[test]
-    fn remove_old_program_filters_unknown_program_does_nothing() {
-        let mut program_to_ports = HashMap::new();
-        let mut filtered_ports = HashMap::new();
-        filtered_ports.insert(DirPort::Egress(99), "f:1".into());
-
-        let removed =
-            remove_old_program_filters(&mut program_to_ports, "nonexistent", &mut filtered_ports);
-
-        assert!(removed.is_empty());
-        assert!(filtered_ports.contains_key(&DirPort::Egress(99)));
-    }
-
-    #[test]
-    fn record_program_port_adds_to_list() {
-        let mut program_to_ports = HashMap::new();
-        record_program_port(&mut program_to_ports, "firefox", DirPort::Ingress(80));
-        record_program_port(&mut program_to_ports, "firefox", DirPort::Egress(443));
-
-        assert_eq!(
-            program_to_ports.get("firefox").unwrap(),
-            &vec![DirPort::Ingress(80), DirPort::Egress(443)],
-        );
-    }
-
-    #[test]
-    fn program_update_clears_old_ports_and_accepts_new() {
-        let mut program_to_ports = HashMap::new();
-        program_to_ports.insert("test".into(), vec![DirPort::Ingress(1234)]);
-        let mut filtered_ports = HashMap::new();
-        filtered_ports.insert(DirPort::Ingress(1234), "old:1".into());
-
-        // Simulate program update: clean old
-        let removed =
-            remove_old_program_filters(&mut program_to_ports, "test", &mut filtered_ports);
-        assert_eq!(removed, vec![(DirPort::Ingress(1234), "old:1".into())]);
-        assert!(!filtered_ports.contains_key(&DirPort::Ingress(1234)));
-
-        // Simulate new ss scan discovering new port
-        record_program_port(&mut program_to_ports, "test", DirPort::Ingress(5678));
-
-        assert_eq!(
-            program_to_ports.get("test").unwrap(),
-            &vec![DirPort::Ingress(5678)],
-        );
-    }
-}
-
-fn handle_ctrlc(root_ingress: QDisc, current_interface: String) {
+/// SIGINT, SIGTERM and SIGHUP stop the shaping and clean up
+fn handle_ctrlc(tx_requests: mpsc::Sender<Request>) {
+    static CAUGHT: AtomicBool = AtomicBool::new(false);
     ctrlc::set_handler(move || {
-        log::warn!("Caught SIGINT signal");
-        let _ = clean_up(&root_ingress.device, &current_interface);
-        std::process::exit(0);
+        // give up on a clean exit if the main loop is stuck
+        if CAUGHT.swap(true, Ordering::SeqCst) {
+            log::warn!("Caught a termination signal again, exiting without cleaning up");
+            std::process::exit(1);
+        }
+        log::warn!("Caught a termination signal");
+        let _ = tx_requests.send(Request {
+            line: "Stop".to_string(),
+            frontend: STDOUT,
+        });
     })
     .expect("Error setting Ctrl-C handler");
 }
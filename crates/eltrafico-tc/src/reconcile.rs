@@ -0,0 +1,311 @@
+//! Bring the classes and filters of our qdiscs to the state the rules ask for
+//!
+//! The desired tree is computed from the rules and the connections, the observed tree is read from
+//! the kernel, and only the difference between them is applied
+use crate::kernel::{ClassState, FilterOptions, FilterState, Handle, QDiscState};
+use crate::tc::{
+    build_default_filter_command, build_htb_class_command, build_remove_htb_class_command,
+    build_remove_u32_filter_command, build_u32_filter_command, default_filter_action,
+    tc_set_allowlist, ClassParams, FilterAction, FilterHandle, PortField, QDisc, PORT_HASH_TABLE,
+};
+use crate::transaction::Transaction;
+use std::collections::{HashMap, HashSet};
+
+/// The classes and filters one of our qdiscs should have
+#[derive(PartialEq, Eq, Debug, Default)]
+pub struct DesiredTree {
+    /// the catch-all filter drops the traffic
+    pub allowlist: bool,
+    /// classes of the limited programs by class id
+    pub classes: HashMap<usize, ClassParams>,
+    /// actions of the filtered ports
+    pub filters: HashMap<usize, FilterAction>,
+}
+
+/// What the kernel has under one of our qdiscs
+#[derive(PartialEq, Eq, Debug, Default)]
+pub struct ObservedTree {
+    /// our htb qdisc, its root and default classes and the port hash table are in place
+    pub intact: bool,
+    /// ids of the classes besides the root and default ones
+    pub classes: HashSet<usize>,
+    /// action of the catch-all filter, if there is one
+    pub default_action: Option<FilterAction>,
+    /// port filters, the action is `None` if it isn't one we set
+    pub filters: HashMap<FilterHandle, Option<FilterAction>>,
+}
+
+/// Read the tree of the qdisc from the kernel state of its device
+pub fn observe(
+    qdisc: &QDisc,
+    qdiscs: &[QDiscState],
+    classes: &[ClassState],
+    filters: &[FilterState],
+) -> ObservedTree {
+    let has_qdisc = qdiscs
+        .iter()
+        .any(|q| q.root && q.kind == "htb" && q.handle == qdisc.handle());
+    let mut class_ids: HashSet<usize> = classes
+        .iter()
+        .filter(|c| c.handle.major == qdisc.id)
+        .map(|c| c.handle.minor)
+        .collect();
+    let has_classes =
+        class_ids.remove(&qdisc.root_class_id) && class_ids.remove(&qdisc.default_class_id);
+
+    let mut observed = ObservedTree {
+        classes: class_ids,
+        ..Default::default()
+    };
+    let hash_table = Handle::new(PORT_HASH_TABLE, 0);
+    let mut has_hash_table = false;
+    let mut has_link = false;
+    for filter in filters.iter().filter(|f| f.parent == qdisc.handle()) {
+        let Some(options) = &filter.options else {
+            continue;
+        };
+        let fh = options.fh.as_deref().unwrap_or_default();
+        match filter.pref {
+            1 if fh.parse() == Ok(hash_table) => has_hash_table = true,
+            1 if options.link == Some(hash_table) => has_link = true,
+            1 => {
+                if let Some(handle) = FilterHandle::parse(fh) {
+                    observed
+                        .filters
+                        .insert(handle, observed_action(qdisc, options));
+                }
+            }
+            2 => {
+                observed.default_action =
+                    observed.default_action.or(observed_action(qdisc, options))
+            }
+            _ => {}
+        }
+    }
+    observed.intact = has_qdisc && has_classes && has_hash_table && has_link;
+    observed
+}
+
+fn observed_action(qdisc: &QDisc, options: &FilterOptions) -> Option<FilterAction> {
+    match options.flowid {
+        Some(flowid) if flowid.major == qdisc.id => Some(FilterAction::Classify(flowid.minor)),
+        Some(_) => None,
+        None => {
+            let drops = options.actions.len() == 1
+                && options.actions[0]["control_action"]["type"] == "drop";
+            drops.then_some(FilterAction::Drop)
+        }
+    }
+}
+
+/// Add the commands that turn the observed tree into the desired one
+///
+/// `applied` are the class params we set last, the kernel doesn't print rates the way we set them.
+/// New classes come before the filters that use them and old ones go after, htb refuses to
+/// delete a class that filters point to
+pub fn plan(
+    transaction: &mut Transaction,
+    qdisc: &QDisc,
+    field: PortField,
+    desired: &DesiredTree,
+    observed: &ObservedTree,
+    applied: &HashMap<usize, ClassParams>,
+) {
+    let mut class_ids: Vec<&usize> = desired.classes.keys().collect();
+    class_ids.sort();
+    for &class_id in class_ids {
+        let params = &desired.classes[&class_id];
+        if !observed.classes.contains(&class_id) {
+            transaction.add(
+                build_htb_class_command("add", qdisc, class_id, params),
+                Some(build_remove_htb_class_command(qdisc, class_id)),
+            );
+        } else if applied.get(&class_id) != Some(params) {
+            transaction.add(
+                build_htb_class_command("change", qdisc, class_id, params),
+                applied
+                    .get(&class_id)
+                    .map(|old| build_htb_class_command("change", qdisc, class_id, old)),
+            );
+        }
+    }
+
+    let mut removed: Vec<(&FilterHandle, &Option<FilterAction>)> = observed
+        .filters
+        .iter()
+        .filter(|(handle, _)| !desired.filters.contains_key(&handle.port()))
+        .collect();
+    removed.sort_by_key(|(handle, _)| handle.port());
+    for (handle, action) in removed {
+        transaction.add(
+            build_remove_u32_filter_command(qdisc, *handle),
+            action
+                .map(|action| build_u32_filter_command("add", qdisc, field, handle.port(), action)),
+        );
+    }
+    let mut ports: Vec<&usize> = desired.filters.keys().collect();
+    ports.sort();
+    for &port in ports {
+        let action = desired.filters[&port];
+        let handle = FilterHandle::of_port(port);
+        match observed.filters.get(&handle) {
+            None => transaction.add(
+                build_u32_filter_command("add", qdisc, field, port, action),
+                Some(build_remove_u32_filter_command(qdisc, handle)),
+            ),
+            Some(old) if *old != Some(action) => transaction.add(
+                build_u32_filter_command("replace", qdisc, field, port, action),
+                old.map(|old| build_u32_filter_command("replace", qdisc, field, port, old)),
+            ),
+            Some(_) => {}
+        }
+    }
+
+    let default_action = default_filter_action(qdisc, desired.allowlist);
+    match observed.default_action {
+        Some(action) if action == default_action => {}
+        Some(_) => tc_set_allowlist(transaction, qdisc, desired.allowlist),
+        None => transaction.add(
+            build_default_filter_command(qdisc, desired.allowlist),
+            Some(format!(
+                "tc filter del dev {} parent {} prio 2",
+                qdisc.device,
+                qdisc.handle()
+            )),
+        ),
+    }
+
+    let mut removed: Vec<&usize> = observed
+        .classes
+        .iter()
+        .filter(|class_id| !desired.classes.contains_key(class_id))
+        .collect();
+    removed.sort();
+    for &class_id in removed {
+        transaction.add(
+            build_remove_htb_class_command(qdisc, class_id),
+            applied
+                .get(&class_id)
+                .map(|old| build_htb_class_command("add", qdisc, class_id, old)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn qdisc() -> QDisc {
+        QDisc {
+            device: "eth0".into(),
+            id: 10,
+            root_class_id: 1,
+            default_class_id: 2,
+        }
+    }
+
+    fn observed_tree(classes: &str, filters: &str) -> ObservedTree {
+        let qdiscs: Vec<QDiscState> = serde_json::from_str(
+            r#"[{"kind":"htb","handle":"a:","root":true,"options":{}},{"kind":"ingress","handle":"ffff:","parent":"ffff:fff1","options":{}}]"#,
+        )
+        .unwrap();
+        let classes: Vec<ClassState> = serde_json::from_str(classes).unwrap();
+        let filters: Vec<FilterState> = serde_json::from_str(filters).unwrap();
+        observe(&qdisc(), &qdiscs, &classes, &filters)
+    }
+
+    const CLASSES: &str = r#"[{"class":"htb","handle":"a:1","root":true},{"class":"htb","handle":"a:2","parent":"a:1"},{"class":"htb","handle":"a:3","parent":"a:1"}]"#;
+    const FILTERS: &str = r#"[
+        {"parent":"a:","protocol":"ip","pref":1,"kind":"u32","options":{"fh":"1:","ht_divisor":256}},
+        {"parent":"a:","protocol":"ip","pref":1,"kind":"u32","options":{"fh":"1:bb:2","flowid":"a:3"}},
+        {"parent":"a:","protocol":"ip","pref":1,"kind":"u32","options":{"fh":"1:50:1","actions":[{"order":1,"kind":"gact","control_action":{"type":"drop"}}]}},
+        {"parent":"a:","protocol":"ip","pref":1,"kind":"u32","options":{"fh":"801::800","link":"1:"}},
+        {"parent":"a:","protocol":"ip","pref":2,"kind":"u32","options":{"fh":"800::800","flowid":"a:2"}}]"#;
+
+    #[test]
+    fn observe_our_tree() {
+        let observed = observed_tree(CLASSES, FILTERS);
+        assert!(observed.intact);
+        assert_eq!(observed.classes, HashSet::from([3]));
+        assert_eq!(observed.default_action, Some(FilterAction::Classify(2)));
+        assert_eq!(
+            observed.filters,
+            HashMap::from([
+                (FilterHandle::of_port(443), Some(FilterAction::Classify(3))),
+                (FilterHandle::of_port(80), Some(FilterAction::Drop)),
+            ])
+        );
+    }
+
+    #[test]
+    fn observe_reset_tree() {
+        // the hash table is gone
+        let filters = r#"[{"parent":"a:","protocol":"ip","pref":2,"kind":"u32","options":{"fh":"800::800","flowid":"a:2"}}]"#;
+        assert!(!observed_tree(CLASSES, filters).intact);
+        // the qdisc is someone else's
+        let qdiscs: Vec<QDiscState> =
+            serde_json::from_str(r#"[{"kind":"fq_codel","handle":"0:","root":true}]"#).unwrap();
+        assert!(!observe(&qdisc(), &qdiscs, &[], &[]).intact);
+    }
+
+    fn planned(desired: &DesiredTree, observed: &ObservedTree) -> Vec<String> {
+        let mut transaction = Transaction::new();
+        let applied = HashMap::from([(3, ClassParams::new(Some("1mbit".into()), None, None))]);
+        plan(
+            &mut transaction,
+            &qdisc(),
+            PortField::Source,
+            desired,
+            observed,
+            &applied,
+        );
+        transaction.commands()
+    }
+
+    #[test]
+    fn plan_nothing_when_in_sync() {
+        let desired = DesiredTree {
+            allowlist: false,
+            classes: HashMap::from([(3, ClassParams::new(Some("1mbit".into()), None, None))]),
+            filters: HashMap::from([(443, FilterAction::Classify(3)), (80, FilterAction::Drop)]),
+        };
+        assert!(planned(&desired, &observed_tree(CLASSES, FILTERS)).is_empty());
+    }
+
+    #[test]
+    fn plan_only_the_delta() {
+        let desired = DesiredTree {
+            allowlist: true,
+            classes: HashMap::from([
+                (3, ClassParams::new(Some("2mbit".into()), None, None)),
+                (4, ClassParams::new(Some("1mbit".into()), None, Some(1))),
+            ]),
+            filters: HashMap::from([
+                (443, FilterAction::Classify(4)),
+                (8080, FilterAction::Classify(3)),
+            ]),
+        };
+        assert_eq!(
+            planned(&desired, &observed_tree(CLASSES, FILTERS)),
+            vec![
+                "tc class change dev eth0 parent a:1 classid a:3 htb rate 8 ceil 2mbit prio 0 quantum 1500",
+                "tc class add dev eth0 parent a:1 classid a:4 htb rate 8 ceil 1mbit prio 1 quantum 1500",
+                "tc filter del dev eth0 parent a: prio 1 handle 1:50:1 protocol ip u32",
+                "tc filter replace dev eth0 parent a: prio 1 protocol ip handle 1:bb:2 u32 ht 1:bb: match ip sport 443 0xffff flowid a:4",
+                "tc filter add dev eth0 parent a: prio 1 protocol ip handle 1:90:20 u32 ht 1:90: match ip sport 8080 0xffff flowid a:3",
+                "tc filter del dev eth0 parent a: prio 2",
+                "tc filter add dev eth0 parent a: prio 2 protocol ip u32 match u32 0 0 action drop",
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_removes_classes_after_their_filters() {
+        let commands = planned(&DesiredTree::default(), &observed_tree(CLASSES, FILTERS));
+        assert_eq!(
+            commands.last().unwrap(),
+            "tc class del dev eth0 classid a:3"
+        );
+        assert_eq!(commands.len(), 3);
+    }
+}
@@ -0,0 +1,684 @@
+use crate::ifb::IfbDevice;
+use crate::ipc::{Directions, InterfaceDump, InterfaceStatus, LimitConfig};
+use crate::journal::{self, Entry};
+use crate::kernel;
+use crate::reconcile::{observe, plan, DesiredTree, ObservedTree};
+use crate::tc::{
+    build_global_rate_commands, find_free_ids, tc_remove_qdisc, tc_setup, ClassParams,
+    FilterAction, PortField, QDisc, RootQDiscSnapshot, INGRESS_QDISC_PARENT_ID,
+};
+use crate::transaction::Transaction;
+use crate::utils::Connection;
+use crate::Result;
+use log::trace;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// What to do with the traffic of a program
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgramRule {
+    /// Limit the traffic on the listed interfaces, `None` means every shaped interface
+    Limit {
+        config: LimitConfig,
+        interfaces: Option<Vec<String>>,
+    },
+    /// Drop all of the traffic on every shaped interface
+    Block,
+}
+
+impl Default for ProgramRule {
+    fn default() -> Self {
+        ProgramRule::Limit {
+            config: LimitConfig::default(),
+            interfaces: None,
+        }
+    }
+}
+
+impl ProgramRule {
+    fn applies_to(&self, interface: &str) -> bool {
+        match self {
+            ProgramRule::Limit {
+                interfaces: Some(interfaces),
+                ..
+            } => interfaces.iter().any(|i| i == interface),
+            _ => true,
+        }
+    }
+
+    /// The ingress and egress classes the program needs on the interface
+    fn class_params(&self, interface: &str) -> (Option<ClassParams>, Option<ClassParams>) {
+        let config = match self {
+            ProgramRule::Limit { config, .. } if self.applies_to(interface) => config,
+            _ => return (None, None),
+        };
+        let LimitConfig {
+            download_rate,
+            download_minimum_rate,
+            upload_rate,
+            upload_minimum_rate,
+            download_priority,
+            upload_priority,
+        } = config.clone();
+        (
+            download_rate
+                .map(|rate| ClassParams::new(Some(rate), download_minimum_rate, download_priority)),
+            upload_rate
+                .map(|rate| ClassParams::new(Some(rate), upload_minimum_rate, upload_priority)),
+        )
+    }
+
+    /// The filter actions to attach to the program ingress and egress ports
+    ///
+    /// `class_ids` are the htb classes of the program on the interface, if it has any
+    fn filter_actions(
+        &self,
+        class_ids: (Option<usize>, Option<usize>),
+    ) -> (Option<FilterAction>, Option<FilterAction>) {
+        match self {
+            ProgramRule::Limit { .. } => (
+                class_ids.0.map(FilterAction::Classify),
+                class_ids.1.map(FilterAction::Classify),
+            ),
+            ProgramRule::Block => (Some(FilterAction::Drop), Some(FilterAction::Drop)),
+        }
+    }
+
+    /// Like `filter_actions` but for allowlist mode, where the default filters drop the traffic
+    ///
+    /// Allowed programs without a limit are sent to the default classes so their traffic passes,
+    /// the other programs get no filters at all so the default filters drop their traffic
+    fn allowlist_filter_actions(
+        &self,
+        class_ids: (Option<usize>, Option<usize>),
+        allowed: bool,
+        ingress_default_class_id: usize,
+        egress_default_class_id: usize,
+    ) -> (Option<FilterAction>, Option<FilterAction>) {
+        if *self == ProgramRule::Block || !allowed {
+            return self.filter_actions(class_ids);
+        }
+        let (ingress_action, egress_action) = self.filter_actions(class_ids);
+        (
+            ingress_action.or(Some(FilterAction::Classify(ingress_default_class_id))),
+            egress_action.or(Some(FilterAction::Classify(egress_default_class_id))),
+        )
+    }
+}
+
+/// Everything the shaping of the interfaces is computed from
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct ShapingState {
+    pub global_limit: LimitConfig,
+    /// in allowlist mode only the traffic of allowed programs passes
+    pub allowlist: bool,
+    pub allowed_programs: HashSet<String>,
+    /// every program we have seen, new programs get a placeholder rule
+    pub program_rules: HashMap<String, ProgramRule>,
+    /// programs gone for longer than the idle timeout, their classes are removed until they return
+    pub idle_programs: HashSet<String>,
+}
+
+/// The traffic shaping setup of one interface
+///
+/// It is cleaned up when dropped, so the interface isn't left shaped on errors and panics
+pub struct ShapedInterface {
+    pub name: String,
+    pub ingress: QDisc,
+    pub egress: QDisc,
+    ifb_device: IfbDevice,
+    /// root qdiscs we replaced, restored on clean up
+    snapshots: Vec<RootQDiscSnapshot>,
+    global_limit: LimitConfig,
+    cleaned_up: bool,
+    /// htb classes of the programs limited on this interface
+    program_classes: HashMap<String, (Option<usize>, Option<usize>)>,
+    /// params of the program classes as we last applied them
+    applied_classes: (HashMap<usize, ClassParams>, HashMap<usize, ClassParams>),
+}
+
+impl ShapedInterface {
+    /// Run `tc_setup` on the interface, the programs get their classes on the first `reconcile`
+    ///
+    /// `used_ifb_devices` are the IFB devices already redirected to by the other interfaces
+    pub fn setup(name: &str, used_ifb_devices: &[String], state: &ShapingState) -> Result<Self> {
+        trace!("running tc_setup on {name}");
+        let mut snapshots: Vec<RootQDiscSnapshot> =
+            RootQDiscSnapshot::take(name)?.into_iter().collect();
+        let ifb_device = IfbDevice::acquire(used_ifb_devices)?;
+        snapshots.extend(RootQDiscSnapshot::take(&ifb_device.name)?);
+        for snapshot in &snapshots {
+            journal::record(Entry::ReplacedRootQDisc(snapshot.clone()));
+        }
+        let (ingress, egress) =
+            match tc_setup(name, &ifb_device.name, &state.global_limit, state.allowlist) {
+                Ok(qdiscs) => qdiscs,
+                Err(e) => {
+                    restore_snapshots(&snapshots)?;
+                    return Err(e);
+                }
+            };
+        Ok(Self {
+            name: name.to_string(),
+            ingress,
+            egress,
+            ifb_device,
+            snapshots,
+            global_limit: state.global_limit.clone(),
+            cleaned_up: false,
+            program_classes: HashMap::new(),
+            applied_classes: Default::default(),
+        })
+    }
+
+    pub fn clean_up(mut self) -> Result<()> {
+        self.tear_down()
+    }
+
+    /// Leave the shaping in place, the journal still has what to remove
+    pub fn detach(mut self) {
+        self.cleaned_up = true;
+        self.ifb_device.detach();
+    }
+
+    fn tear_down(&mut self) -> Result<()> {
+        if self.cleaned_up {
+            return Ok(());
+        }
+        self.cleaned_up = true;
+        clean_up(&self.ingress.device, &self.name)?;
+        restore_snapshots(&self.snapshots)?;
+        self.ifb_device.release()
+    }
+
+    /// Apply the difference between what the state asks for and what the kernel has
+    ///
+    /// If our qdiscs were removed or replaced from outside, they are set up again
+    pub fn reconcile(
+        &mut self,
+        state: &ShapingState,
+        connections: &HashMap<String, Vec<Connection>>,
+    ) -> Result<()> {
+        let (mut ingress, mut egress) = self.observe()?;
+        if !ingress.intact || !egress.intact {
+            log::warn!(
+                "The shaping of {} was changed from outside, setting it up again",
+                self.name
+            );
+            self.set_up_again(state)?;
+            (ingress, egress) = self.observe()?;
+        }
+
+        let class_params = desired_class_params(state, &self.name);
+        // the ids are only kept once the kernel has their classes
+        let mut program_classes = self.program_classes.clone();
+        let (ingress_classes, egress_classes) = assign_class_ids(
+            &mut program_classes,
+            &class_params,
+            (&ingress, &egress),
+            (&self.ingress, &self.egress),
+        );
+        let (ingress_filters, egress_filters) = desired_filters(
+            state,
+            &program_classes,
+            connections,
+            (self.ingress.default_class_id, self.egress.default_class_id),
+        );
+        let ingress_tree = DesiredTree {
+            allowlist: state.allowlist,
+            classes: ingress_classes,
+            filters: ingress_filters,
+        };
+        let egress_tree = DesiredTree {
+            allowlist: state.allowlist,
+            classes: egress_classes,
+            filters: egress_filters,
+        };
+
+        let mut transaction = Transaction::new();
+        if self.global_limit != state.global_limit {
+            let commands =
+                build_global_rate_commands(&self.ingress, &self.egress, &state.global_limit);
+            // going back to the current limit undoes the change
+            let undo = build_global_rate_commands(&self.ingress, &self.egress, &self.global_limit);
+            for (command, undo) in commands.into_iter().zip(undo) {
+                transaction.add(command, Some(undo));
+            }
+        }
+        plan(
+            &mut transaction,
+            &self.ingress,
+            PortField::Destination,
+            &ingress_tree,
+            &ingress,
+            &self.applied_classes.0,
+        );
+        plan(
+            &mut transaction,
+            &self.egress,
+            PortField::Source,
+            &egress_tree,
+            &egress,
+            &self.applied_classes.1,
+        );
+        transaction.commit()?;
+
+        self.program_classes = program_classes;
+        self.global_limit = state.global_limit.clone();
+        self.applied_classes = (ingress_tree.classes, egress_tree.classes);
+        Ok(())
+    }
+
+    pub fn status(&self) -> InterfaceStatus {
+        InterfaceStatus {
+            name: self.name.clone(),
+            ifb_device: self.ingress.device.clone(),
+            ingress_qdisc: self.ingress.handle(),
+            egress_qdisc: self.egress.handle(),
+        }
+    }
+
+    /// The program classes and the port filters that are in the kernel
+    pub fn dump(&self, connections: &HashMap<String, Vec<Connection>>) -> Result<InterfaceDump> {
+        let (ingress, egress) = self.observe()?;
+        let classes = self
+            .program_classes
+            .iter()
+            .map(|(program, (ingress_id, egress_id))| {
+                let classes = Directions {
+                    ingress: ingress_id.map(|id| self.ingress.class(id)),
+                    egress: egress_id.map(|id| self.egress.class(id)),
+                };
+                (program.clone(), classes)
+            })
+            .collect();
+
+        // a port shared by two programs is filtered for the first one
+        let mut port_programs: HashMap<usize, &str> = HashMap::new();
+        let mut programs: Vec<&String> = connections.keys().collect();
+        programs.sort();
+        for program in programs {
+            for connection in &connections[program] {
+                port_programs.entry(connection.lport).or_insert(program);
+            }
+        }
+        let mut filtered_ports: BTreeMap<String, Directions<Vec<usize>>> = BTreeMap::new();
+        let program_of = |port: usize| port_programs.get(&port).copied().unwrap_or("?");
+        for handle in ingress.filters.keys() {
+            let ports = filtered_ports.entry(program_of(handle.port()).to_string());
+            ports.or_default().ingress.push(handle.port());
+        }
+        for handle in egress.filters.keys() {
+            let ports = filtered_ports.entry(program_of(handle.port()).to_string());
+            ports.or_default().egress.push(handle.port());
+        }
+        for ports in filtered_ports.values_mut() {
+            ports.ingress.sort();
+            ports.egress.sort();
+        }
+
+        Ok(InterfaceDump {
+            name: self.name.clone(),
+            classes,
+            filtered_ports,
+        })
+    }
+
+    fn observe(&self) -> Result<(ObservedTree, ObservedTree)> {
+        let observe_qdisc = |qdisc: &QDisc| -> Result<ObservedTree> {
+            let device = &qdisc.device;
+            Ok(observe(
+                qdisc,
+                &kernel::qdiscs(device)?,
+                &kernel::classes(device)?,
+                &kernel::filters(device)?,
+            ))
+        };
+        let ingress = observe_qdisc(&self.ingress)?;
+        let mut egress = observe_qdisc(&self.egress)?;
+        // without the ingress qdisc nothing gets redirected to the IFB device
+        egress.intact &= kernel::qdiscs(&self.name)?.iter().any(|q| q.is_ingress());
+        Ok((ingress, egress))
+    }
+
+    /// Replace whatever is left of our setup with a fresh one
+    fn set_up_again(&mut self, state: &ShapingState) -> Result<()> {
+        clean_up(&self.ingress.device, &self.name)?;
+        let (ingress, egress) = tc_setup(
+            &self.name,
+            &self.ingress.device,
+            &state.global_limit,
+            state.allowlist,
+        )?;
+        self.ingress = ingress;
+        self.egress = egress;
+        self.global_limit = state.global_limit.clone();
+        self.program_classes.clear();
+        self.applied_classes = Default::default();
+        Ok(())
+    }
+}
+
+impl Drop for ShapedInterface {
+    fn drop(&mut self) {
+        if let Err(e) = self.tear_down() {
+            log::warn!("Failed to clean up {}: {e}", self.name);
+        }
+    }
+}
+
+fn restore_snapshots(snapshots: &[RootQDiscSnapshot]) -> Result<()> {
+    for snapshot in snapshots {
+        snapshot.restore()?;
+        journal::record_removal(Entry::ReplacedRootQDisc(snapshot.clone()));
+    }
+    Ok(())
+}
+
+fn clean_up(ingress_device: &str, egress_device: &str) -> Result<()> {
+    log::info!("Cleaning up QDiscs");
+    tc_remove_qdisc(ingress_device.into(), None)?;
+    journal::record_removal(Entry::RootQDisc(ingress_device.into()));
+    tc_remove_qdisc(egress_device.into(), None)?;
+    journal::record_removal(Entry::RootQDisc(egress_device.into()));
+    tc_remove_qdisc(egress_device.into(), Some(INGRESS_QDISC_PARENT_ID.into()))?;
+    journal::record_removal(Entry::IngressQDisc(egress_device.into()));
+    Ok(())
+}
+
+/// The classes each program needs on the interface, idle programs need none
+fn desired_class_params<'a>(
+    state: &'a ShapingState,
+    interface: &str,
+) -> HashMap<&'a str, (Option<ClassParams>, Option<ClassParams>)> {
+    state
+        .program_rules
+        .iter()
+        .filter(|(program, _)| !state.idle_programs.contains(*program))
+        .map(|(program, rule)| (program.as_str(), rule.class_params(interface)))
+        .collect()
+}
+
+/// Keep the class ids of the programs that still need a class and pick free ones for the others
+///
+/// Returns the desired ingress and egress classes
+fn assign_class_ids(
+    program_classes: &mut HashMap<String, (Option<usize>, Option<usize>)>,
+    class_params: &HashMap<&str, (Option<ClassParams>, Option<ClassParams>)>,
+    observed: (&ObservedTree, &ObservedTree),
+    qdiscs: (&QDisc, &QDisc),
+) -> (HashMap<usize, ClassParams>, HashMap<usize, ClassParams>) {
+    // cleared limits free their classes
+    program_classes.retain(|program, (ingress_id, egress_id)| {
+        let (ingress, egress) = class_params
+            .get(program.as_str())
+            .map_or((false, false), |(i, e)| (i.is_some(), e.is_some()));
+        if !ingress {
+            *ingress_id = None;
+        }
+        if !egress {
+            *egress_id = None;
+        }
+        ingress_id.is_some() || egress_id.is_some()
+    });
+
+    let used = |observed: &ObservedTree, qdisc: &QDisc| -> HashSet<usize> {
+        let mut used = observed.classes.clone();
+        used.extend([qdisc.root_class_id, qdisc.default_class_id]);
+        used
+    };
+    let mut used_ingress = used(observed.0, qdiscs.0);
+    let mut used_egress = used(observed.1, qdiscs.1);
+    for (ingress_id, egress_id) in program_classes.values() {
+        used_ingress.extend(*ingress_id);
+        used_egress.extend(*egress_id);
+    }
+
+    let mut ingress_classes = HashMap::new();
+    let mut egress_classes = HashMap::new();
+    let mut programs: Vec<&&str> = class_params.keys().collect();
+    programs.sort();
+    for program in programs {
+        let (ingress, egress) = &class_params[*program];
+        if ingress.is_none() && egress.is_none() {
+            continue;
+        }
+        let ids = program_classes.entry(program.to_string()).or_default();
+        let assign = |id: &mut Option<usize>, used: &mut HashSet<usize>| {
+            *id.get_or_insert_with(|| {
+                let id = find_free_ids(used.iter().copied());
+                used.insert(id);
+                id
+            })
+        };
+        if let Some(params) = ingress {
+            ingress_classes.insert(assign(&mut ids.0, &mut used_ingress), params.clone());
+        }
+        if let Some(params) = egress {
+            egress_classes.insert(assign(&mut ids.1, &mut used_egress), params.clone());
+        }
+    }
+    (ingress_classes, egress_classes)
+}
+
+/// The actions of the ingress and egress filters of the connection ports
+fn desired_filters(
+    state: &ShapingState,
+    program_classes: &HashMap<String, (Option<usize>, Option<usize>)>,
+    connections: &HashMap<String, Vec<Connection>>,
+    default_class_ids: (usize, usize),
+) -> (HashMap<usize, FilterAction>, HashMap<usize, FilterAction>) {
+    let mut ingress_filters = HashMap::new();
+    let mut egress_filters = HashMap::new();
+    // a port shared by two programs goes to the first one, in the same order every time
+    let mut programs: Vec<&String> = connections.keys().collect();
+    programs.sort();
+    for program in programs {
+        let Some(rule) = state.program_rules.get(program) else {
+            continue;
+        };
+        let class_ids = program_classes.get(program).copied().unwrap_or_default();
+        let (ingress_action, egress_action) = if state.allowlist {
+            rule.allowlist_filter_actions(
+                class_ids,
+                state.allowed_programs.contains(program),
+                default_class_ids.0,
+                default_class_ids.1,
+            )
+        } else {
+            rule.filter_actions(class_ids)
+        };
+        for connection in &connections[program] {
+            if let Some(action) = ingress_action {
+                ingress_filters.entry(connection.lport).or_insert(action);
+            }
+            if let Some(action) = egress_action {
+                egress_filters.entry(connection.lport).or_insert(action);
+            }
+        }
+    }
+    (ingress_filters, egress_filters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_rule_filter_actions() {
+        assert_eq!(
+            ProgramRule::default().filter_actions((Some(3), None)),
+            (Some(FilterAction::Classify(3)), None)
+        );
+        assert_eq!(
+            ProgramRule::Block.filter_actions((None, None)),
+            (Some(FilterAction::Drop), Some(FilterAction::Drop))
+        );
+    }
+
+    #[test]
+    fn program_rule_allowlist_filter_actions() {
+        assert_eq!(
+            ProgramRule::default().allowlist_filter_actions((None, None), false, 2, 2),
+            (None, None)
+        );
+        assert_eq!(
+            ProgramRule::default().allowlist_filter_actions((Some(3), None), true, 2, 2),
+            (
+                Some(FilterAction::Classify(3)),
+                Some(FilterAction::Classify(2))
+            )
+        );
+        assert_eq!(
+            ProgramRule::Block.allowlist_filter_actions((None, None), true, 2, 2),
+            (Some(FilterAction::Drop), Some(FilterAction::Drop))
+        );
+    }
+
+    #[test]
+    fn program_rule_applies_to_listed_interfaces() {
+        let rule = ProgramRule::Limit {
+            config: LimitConfig::default(),
+            interfaces: Some(vec!["eth0".into()]),
+        };
+        assert!(rule.applies_to("eth0"));
+        assert!(!rule.applies_to("wlan0"));
+        assert!(ProgramRule::default().applies_to("wlan0"));
+        assert!(ProgramRule::Block.applies_to("wlan0"));
+    }
+
+    fn connection(lport: usize) -> Connection {
+        Connection {
+            laddr: "127.0.0.1".into(),
+            lport,
+            raddr: "127.0.0.1".into(),
+            rport: 443,
+            pid: 1,
+        }
+    }
+
+    fn qdisc(id: usize) -> QDisc {
+        QDisc {
+            device: "eth0".into(),
+            id,
+            root_class_id: 1,
+            default_class_id: 2,
+        }
+    }
+
+    fn limit(download_rate: Option<&str>, upload_rate: Option<&str>) -> ProgramRule {
+        ProgramRule::Limit {
+            config: LimitConfig {
+                download_rate: download_rate.map(Into::into),
+                upload_rate: upload_rate.map(Into::into),
+                ..Default::default()
+            },
+            interfaces: None,
+        }
+    }
+
+    #[test]
+    fn assign_class_ids_keeps_and_frees_ids() {
+        let mut program_classes = HashMap::from([
+            ("firefox".to_string(), (Some(3), Some(3))),
+            ("curl".to_string(), (Some(4), None)),
+        ]);
+        let firefox = limit(Some("1mbit"), None).class_params("eth0");
+        let wget = limit(Some("2mbit"), Some("1mbit")).class_params("eth0");
+        let class_params = HashMap::from([
+            ("firefox", firefox.clone()),
+            ("curl", (None, None)),
+            ("wget", wget.clone()),
+        ]);
+        let observed = ObservedTree {
+            classes: HashSet::from([3, 4, 5]),
+            ..Default::default()
+        };
+
+        let (ingress, egress) = assign_class_ids(
+            &mut program_classes,
+            &class_params,
+            (&observed, &ObservedTree::default()),
+            (&qdisc(1), &qdisc(2)),
+        );
+
+        // class 4 is still in the kernel until it gets removed, 5 isn't ours
+        assert_eq!(program_classes["firefox"], (Some(3), None));
+        assert_eq!(program_classes["wget"], (Some(6), Some(3)));
+        assert!(!program_classes.contains_key("curl"));
+        assert_eq!(
+            ingress,
+            HashMap::from([(3, firefox.0.unwrap()), (6, wget.0.unwrap())])
+        );
+        assert_eq!(egress, HashMap::from([(3, wget.1.unwrap())]));
+    }
+
+    #[test]
+    fn idle_programs_need_no_classes() {
+        let mut state = ShapingState::default();
+        state
+            .program_rules
+            .insert("firefox".into(), limit(Some("1mbit"), Some("1mbit")));
+        state
+            .program_rules
+            .insert("curl".into(), limit(Some("1mbit"), None));
+        state.idle_programs.insert("firefox".into());
+
+        let class_params = desired_class_params(&state, "eth0");
+        assert!(!class_params.contains_key("firefox"));
+        assert!(class_params["curl"].0.is_some() && class_params["curl"].1.is_none());
+    }
+
+    #[test]
+    fn program_update_clears_old_ports_and_accepts_new() {
+        let mut state = ShapingState::default();
+        state
+            .program_rules
+            .insert("test".into(), limit(Some("1mbit"), None));
+        let program_classes = HashMap::from([("test".to_string(), (Some(3), None))]);
+        let connections = HashMap::from([("test".to_string(), vec![connection(1234)])]);
+
+        let (ingress, egress) = desired_filters(&state, &program_classes, &connections, (2, 2));
+        assert_eq!(ingress, HashMap::from([(1234, FilterAction::Classify(3))]));
+        assert!(egress.is_empty());
+
+        // the limit is cleared and the program moved to a new port
+        state
+            .program_rules
+            .insert("test".into(), ProgramRule::default());
+        let connections = HashMap::from([("test".to_string(), vec![connection(5678)])]);
+        let (ingress, _) = desired_filters(&state, &HashMap::new(), &connections, (2, 2));
+        assert!(ingress.is_empty());
+
+        state
+            .program_rules
+            .insert("test".into(), ProgramRule::Block);
+        let (ingress, egress) = desired_filters(&state, &HashMap::new(), &connections, (2, 2));
+        assert_eq!(ingress, HashMap::from([(5678, FilterAction::Drop)]));
+        assert_eq!(egress, HashMap::from([(5678, FilterAction::Drop)]));
+    }
+
+    #[test]
+    fn desired_filters_in_allowlist_mode() {
+        let mut state = ShapingState {
+            allowlist: true,
+            ..Default::default()
+        };
+        state
+            .program_rules
+            .insert("firefox".into(), ProgramRule::default());
+        state
+            .program_rules
+            .insert("curl".into(), ProgramRule::default());
+        state.allowed_programs.insert("firefox".into());
+        let connections = HashMap::from([
+            ("firefox".to_string(), vec![connection(1234)]),
+            ("curl".to_string(), vec![connection(5678)]),
+        ]);
+
+        let (ingress, egress) = desired_filters(&state, &HashMap::new(), &connections, (2, 2));
+        assert_eq!(ingress, HashMap::from([(1234, FilterAction::Classify(2))]));
+        assert_eq!(egress, HashMap::from([(1234, FilterAction::Classify(2))]));
+    }
+}
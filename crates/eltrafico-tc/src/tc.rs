@@ -1,7 +1,9 @@
 use std::collections::HashSet;
 
 use crate::ipc::LimitConfig;
-use crate::utils::ifconfig;
+use crate::journal::Entry;
+use crate::kernel::{self, Handle, QDiscState};
+use crate::transaction::Transaction;
 use crate::{run, run_out, Result};
 
 const MIN_RATE: &str = "8";
@@ -21,53 +23,20 @@ pub struct QDisc {
     pub default_class_id: usize,
 }
 
-//FIXME
-fn _clean_up(remove_ifb_device: bool, shutdown_ifb_device: Option<String>) -> Result<()> {
-    log::info!("Cleaning up IFB device");
-    if remove_ifb_device {
-        run!("rmmod ifb")
-    } else {
-        run!("ip link set dev {shutdown_ifb_device:?} down")
+impl QDisc {
+    /// `id:`
+    pub fn handle(&self) -> Handle {
+        Handle::new(self.id, 0)
     }
-}
-
-fn activate_device(name: &str) -> Result<()> {
-    run!("ip link set dev {} up", name)
-}
-
-fn create_ifb_device() -> Result<String> {
-    let before: HashSet<String> = ifconfig()?.into_iter().map(|i| i.name).collect();
-    run!("modprobe ifb numifbs=1")?;
-    let after: HashSet<String> = ifconfig()?.into_iter().map(|i| i.name).collect();
-    let created_interface_name = after
-        .difference(&before)
-        .next()
-        .ok_or("Error creating  interface")?;
-
-    activate_device(created_interface_name)?;
-    Ok(created_interface_name.to_string())
-}
 
-fn acquire_ifb_device() -> Result<String> {
-    let interfaces = ifconfig()?;
-    if let Some(interface) = interfaces.iter().find(|i| i.name.starts_with("ifb")) {
-        if !interface.is_up() {
-            activate_device(&interface.name)?;
-            //TODO
-            //
-            //# Deactivate existing IFB device if it wasn't activated
-            //atexit.register(_clean_up, shutdown_ifb_device=device_name)
-        }
-        Ok(interface.name.to_string())
-    } else {
-        //TODO
-        // # Clean up IFB device if it was created
-        // atexit.register(_clean_up, remove_ifb_device=True)
-        create_ifb_device()
+    /// `id:class_id`
+    pub fn class(&self, class_id: usize) -> Handle {
+        Handle::new(self.id, class_id)
     }
 }
 
-fn find_free_ids(ids: impl Iterator<Item = usize>) -> usize {
+/// The lowest id, starting at 1, that isn't in `ids`
+pub fn find_free_ids(ids: impl Iterator<Item = usize>) -> usize {
     let set: HashSet<_> = ids.collect();
     let mut current = 1;
     while set.contains(&current) {
@@ -77,162 +46,314 @@ fn find_free_ids(ids: impl Iterator<Item = usize>) -> usize {
 }
 
 fn get_free_qdisc_id(device: &str) -> Result<usize> {
-    let output = run_out!("tc qdisc show dev {device}")??;
+    let qdiscs = kernel::qdiscs(device)?;
+    Ok(find_free_ids(qdiscs.iter().map(|q| q.handle.major)))
+}
 
-    let mut ids: Vec<usize> = vec![];
-    for line in output.lines() {
-        if !line.starts_with("qdisc") {
-            log::warn!("Failed to parse line: {line}");
-            continue;
-        }
-        if let Some(p) = line.split_whitespace().nth(2) {
-            let mut p = p.split(':');
-            if let Some(qdisc_id) = p.next() {
-                let qdisc_id = match qdisc_id.parse() {
-                    Ok(id) => id,
-                    Err(_id) => {
-                        // This should only happen for the ingress QDisc `qdisc ingress ffff:`
-                        usize::from_str_radix(qdisc_id, 16)?
-                    }
-                };
-                ids.push(qdisc_id);
-            }
+/// A root qdisc that was configured before us, it is restored when we are done with the device
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct RootQDiscSnapshot {
+    pub device: String,
+    pub kind: String,
+    pub handle: Handle,
+    /// Options as printed by `tc qdisc show`
+    pub options: String,
+}
+
+impl RootQDiscSnapshot {
+    /// Snapshot the root qdisc of the device, `None` means the kernel default is installed
+    ///
+    /// Fails if the existing setup can't be restored or conflicts with ours
+    pub fn take(device: &str) -> Result<Option<Self>> {
+        let qdiscs = kernel::qdiscs(device)?;
+        let has_classes = !kernel::classes(device)?.is_empty();
+        let has_filters = !kernel::filters(device)?.is_empty();
+        let Some(root) = check_existing_qdiscs(device, &qdiscs, has_classes, has_filters)? else {
+            return Ok(None);
+        };
+        // the json options don't use the names tc parses
+        let options = root_qdisc_options(&run_out!("tc qdisc show dev {device} root")??);
+        Ok(Some(Self {
+            device: device.to_string(),
+            kind: root.kind.clone(),
+            handle: root.handle,
+            options,
+        }))
+    }
+
+    /// Put the qdisc back in place of ours
+    pub fn restore(&self) -> Result<()> {
+        let Self {
+            device,
+            kind,
+            handle,
+            options,
+        } = self;
+        log::info!("Restoring the {kind} qdisc of {device}");
+        let output = crate::utils::run(format!(
+            "tc qdisc replace dev {device} root handle {handle} {kind} {options}"
+        ))?;
+        if !output.status.success() {
+            // tc doesn't always print the options the way it parses them
+            log::warn!(
+                "Failed to restore the options of the {kind} qdisc of {device}, using its defaults"
+            );
+            run!("tc qdisc replace dev {device} root handle {handle} {kind}")?;
         }
+        Ok(())
     }
-    Ok(find_free_ids(ids.into_iter()))
 }
 
-fn get_free_class_id(interface: &str, qdisc_id: usize) -> crate::Result<usize> {
-    let output = run_out!("tc class show dev {}", interface)??;
-    let mut ids: Vec<usize> = vec![];
-    for line in output.lines() {
-        if !line.starts_with("class") {
-            log::warn!("Failed to parse line: {line}");
-            continue;
-        }
-        if let Some(p) = line.split_whitespace().nth(2) {
-            let mut p = p.split(':');
-            let current_qdisc_id = p.next();
-            if let Some(current_qdisc_id) = current_qdisc_id {
-                if current_qdisc_id.parse::<usize>()? == qdisc_id {
-                    if let Some(class_id) = p.next() {
-                        ids.push(class_id.parse()?);
-                    }
-                }
-            }
-        }
+/// Qdiscs whose classes are all added by the user, other qdiscs like tbf or prio come with their own
+const USER_CLASSES_QDISCS: [&str; 5] = ["htb", "hfsc", "cbq", "drr", "qfq"];
+
+/// The root qdisc to snapshot, if it isn't the kernel default
+fn check_existing_qdiscs<'a>(
+    device: &str,
+    qdiscs: &'a [QDiscState],
+    has_classes: bool,
+    has_filters: bool,
+) -> Result<Option<&'a QDiscState>> {
+    if let Some(ingress) = qdiscs.iter().find(|q| q.is_ingress()) {
+        let kind = &ingress.kind;
+        return Err(format!(
+            "{device} already has a {kind} qdisc, remove it with `tc qdisc del dev {device} {kind}` to shape it"
+        )
+        .into());
+    }
+    let Some(root) = qdiscs.iter().find(|q| q.root) else {
+        return Ok(None);
+    };
+    // the kernel default, like noqueue or mq with a qdisc per queue, comes back when ours is removed
+    if root.is_default() {
+        return Ok(None);
+    }
+    let kind = &root.kind;
+    let user_classes = has_classes && USER_CLASSES_QDISCS.contains(&kind.as_str());
+    let has_children = qdiscs.iter().any(|q| !q.root);
+    if user_classes || has_filters || has_children {
+        return Err(format!(
+            "{device} already has a {kind} qdisc setup with classes or filters that can't be restored, remove it with `tc qdisc del dev {device} root` to shape it"
+        )
+        .into());
     }
-    Ok(find_free_ids(ids.into_iter()))
+    Ok(Some(root))
+}
+
+/// The options of `qdisc tbf 8001: root refcnt 2 rate 1Mbit burst 4Kb lat 400ms`
+fn root_qdisc_options(qdisc: &str) -> String {
+    let Some(line) = qdisc.lines().find(|line| line.starts_with("qdisc")) else {
+        return String::new();
+    };
+    let mut options: Vec<&str> = line.split_whitespace().skip(4).collect();
+    if options.first() == Some(&"refcnt") {
+        options.drain(..options.len().min(2));
+    }
+    options.join(" ")
 }
 
 pub fn tc_setup(
-    device: String,
-    download_rate: Option<String>,
-    download_minimum_rate: Option<String>,
-    upload_rate: Option<String>,
-    upload_minimum_rate: Option<String>,
-    default_download_priority: Option<usize>,
-    default_upload_priority: Option<usize>,
+    device: &str,
+    ifb_device: &str,
+    global_limit: &LimitConfig,
+    allowlist: bool,
 ) -> Result<(QDisc, QDisc)> {
     // Rust way to mimic python optional
+    let LimitConfig {
+        download_rate,
+        download_minimum_rate,
+        upload_rate,
+        upload_minimum_rate,
+        download_priority,
+        upload_priority,
+    } = global_limit.clone();
     let download_rate = download_rate.unwrap_or_else(|| MAX_RATE.into());
     let download_minimum_rate = download_minimum_rate.unwrap_or_else(|| MIN_RATE.into());
     let upload_rate = upload_rate.unwrap_or_else(|| MAX_RATE.into());
     let upload_minimum_rate = upload_minimum_rate.unwrap_or_else(|| MIN_RATE.into());
-    let default_download_priority = default_download_priority.unwrap_or(0);
-    let default_upload_priority = default_upload_priority.unwrap_or(0);
-
-    // set up IFB device
-    run!("tc qdisc add dev {device} handle ffff: ingress")?;
-    let ifb_device = acquire_ifb_device()?;
-    run!(
-        "tc filter add dev {device} parent ffff: protocol ip u32 match u32 0 0 action mirred egress redirect dev {ifb_device}"
-    )?;
-
-    // Create IFB device QDisc and root class limited at download_rate
-    let ifb_device_qdisc_id = get_free_qdisc_id(&ifb_device)?;
-    run!("tc qdisc add dev {ifb_device} root handle {ifb_device_qdisc_id}: htb",)?;
-    let ifb_device_root_class_id = get_free_class_id(&ifb_device, ifb_device_qdisc_id)?;
-    run!(
-        "tc class add dev {ifb_device} parent {ifb_device_qdisc_id}: classid {ifb_device_qdisc_id}:{ifb_device_root_class_id} htb rate {download_rate} quantum 1500"
-    )?;
+    let default_download_priority = download_priority.unwrap_or(0);
+    let default_upload_priority = upload_priority.unwrap_or(0);
 
-    let ifb_default_class_id = tc_add_htb_class(
-        &QDisc {
-            device: ifb_device.clone(),
-            id: ifb_device_qdisc_id,
-            root_class_id: ifb_device_root_class_id,
-            default_class_id: 0,
-        },
-        Some(download_rate),
-        Some(download_minimum_rate),
-        Some(default_download_priority),
-    )?;
+    // the qdiscs are new, so are the ids of their classes
     let ingress_qdisc = QDisc {
-        device: ifb_device.clone(),
-        id: ifb_device_qdisc_id,
-        root_class_id: ifb_device_root_class_id,
-        default_class_id: ifb_default_class_id,
+        device: ifb_device.to_string(),
+        id: get_free_qdisc_id(ifb_device)?,
+        root_class_id: 1,
+        default_class_id: 2,
     };
-    run!(
-        "tc filter add dev {ifb_device} parent {ifb_device_qdisc_id}: prio 2 protocol ip u32 match u32 0 0 flowid {ifb_device_qdisc_id}:{ifb_default_class_id}"
-    )?;
-
-    // Create interface QDisc and root class limited at upload_rate
-    let device_qdisc_id = get_free_qdisc_id(&device)?;
-    run!("tc qdisc add dev {device} root handle {device_qdisc_id}: htb",)?;
-    let device_root_class_id = get_free_class_id(&device, device_qdisc_id)?;
-
-    run!(
-        "tc class add dev {device} parent {device_qdisc_id}: classid {device_qdisc_id}:{device_root_class_id} htb rate {upload_rate} quantum 1500"
-    )?;
-
-    let device_default_class_id = tc_add_htb_class(
-        &QDisc {
-            device: device.to_string(),
-            id: device_qdisc_id,
-            root_class_id: device_root_class_id,
-            default_class_id: 0,
-        },
-        Some(upload_rate),
-        Some(upload_minimum_rate),
-        Some(default_upload_priority),
-    )?;
     let egress_qdisc = QDisc {
         device: device.to_string(),
-        id: device_qdisc_id,
-        root_class_id: device_root_class_id,
-        default_class_id: device_default_class_id,
+        id: get_free_qdisc_id(device)?,
+        root_class_id: 1,
+        default_class_id: 2,
     };
-    run!(
-        "tc filter add dev {device} parent {device_qdisc_id}: prio 2 protocol ip u32 match u32 0 0 flowid {device_qdisc_id}:{device_default_class_id}"
-    )?;
+    let mut transaction = Transaction::new();
+
+    // set up IFB device
+    transaction.add_journaled(
+        format!("tc qdisc add dev {device} handle ffff: ingress"),
+        format!("tc qdisc del dev {device} ingress"),
+        Entry::IngressQDisc(device.to_string()),
+    );
+    transaction.add(
+        format!("tc filter add dev {device} parent ffff: protocol ip u32 match u32 0 0 action mirred egress redirect dev {ifb_device}"),
+        None,
+    );
 
+    // Create IFB device QDisc and root class limited at download_rate
+    add_root_htb(
+        &mut transaction,
+        &ingress_qdisc,
+        download_rate,
+        download_minimum_rate,
+        default_download_priority,
+        allowlist,
+    );
+    add_port_hash_table(&mut transaction, &ingress_qdisc, PortField::Destination);
+    // Create interface QDisc and root class limited at upload_rate
+    add_root_htb(
+        &mut transaction,
+        &egress_qdisc,
+        upload_rate,
+        upload_minimum_rate,
+        default_upload_priority,
+        allowlist,
+    );
+    add_port_hash_table(&mut transaction, &egress_qdisc, PortField::Source);
+
+    transaction.commit()?;
     Ok((ingress_qdisc, egress_qdisc))
 }
 
-pub fn tc_add_htb_class(
+/// Replace the root qdisc that was snapshotted before with an htb qdisc,
+/// its root class limited at `rate` and its default class
+fn add_root_htb(
+    transaction: &mut Transaction,
     qdisc: &QDisc,
-    ceil: Option<String>,
-    rate: Option<String>,
-    priority: Option<usize>,
-) -> Result<usize> {
-    let ceil = ceil.unwrap_or_else(|| MAX_RATE.into());
-    let rate = rate.unwrap_or_else(|| MIN_RATE.into());
-    let priority = priority.unwrap_or(0);
-    let class_id = get_free_class_id(&qdisc.device, qdisc.id)?;
+    rate: String,
+    minimum_rate: String,
+    priority: usize,
+    allowlist: bool,
+) {
+    let device = &qdisc.device;
+    let handle = qdisc.handle();
+    let root_class = qdisc.class(qdisc.root_class_id);
+    transaction.add_journaled(
+        format!("tc qdisc replace dev {device} root handle {handle} htb"),
+        format!("tc qdisc del dev {device} root"),
+        Entry::RootQDisc(device.clone()),
+    );
+    // the classes and filters go away with the qdisc
+    transaction.add(
+        format!("tc class add dev {device} parent {handle} classid {root_class} htb rate {rate} quantum 1500"),
+        None,
+    );
+    let default_class = ClassParams::new(Some(rate), Some(minimum_rate), Some(priority));
+    transaction.add(
+        build_htb_class_command("add", qdisc, qdisc.default_class_id, &default_class),
+        None,
+    );
+    transaction.add(build_default_filter_command(qdisc, allowlist), None);
+}
+
+/// Add the hash table of the port filters and send every packet to the bucket of its port
+fn add_port_hash_table(transaction: &mut Transaction, qdisc: &QDisc, field: PortField) {
+    let device = &qdisc.device;
+    let handle = qdisc.handle();
+    transaction.add(
+        format!("tc filter add dev {device} parent {handle} prio 1 protocol ip handle {PORT_HASH_TABLE:x}: u32 divisor {PORT_HASH_BUCKETS}"),
+        None,
+    );
+    transaction.add(
+        format!(
+            "tc filter add dev {device} parent {handle} prio 1 protocol ip u32 link {PORT_HASH_TABLE:x}: hashkey mask {} at 20 match u32 0 0",
+            field.hash_mask()
+        ),
+        None,
+    );
+}
+
+/// Rates and priority of an htb class under the root class
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ClassParams {
+    pub ceil: String,
+    pub rate: String,
+    pub priority: usize,
+}
+
+impl ClassParams {
+    /// A class without a ceil can use the whole root class, without a rate it only borrows
+    pub fn new(ceil: Option<String>, rate: Option<String>, priority: Option<usize>) -> Self {
+        Self {
+            ceil: ceil.unwrap_or_else(|| MAX_RATE.into()),
+            rate: rate.unwrap_or_else(|| MIN_RATE.into()),
+            priority: priority.unwrap_or(0),
+        }
+    }
+}
+
+/// `verb` is `add` to create the class or `change` to update it in place
+pub fn build_htb_class_command(
+    verb: &str,
+    qdisc: &QDisc,
+    class_id: usize,
+    params: &ClassParams,
+) -> String {
     // rate of 1byte/s is the lowest we can specify. All classes added this way should
     // only be allowed to borrow from the parent class, otherwise it's possible to
     // specify a rate higher than the global rate
-    run!(
-        "tc class add dev {} parent {}:{} classid {}:{class_id} htb rate {rate} ceil {ceil} prio {priority} quantum 1500"
-        ,qdisc.device
-        ,qdisc.id
-        ,qdisc.root_class_id
-        ,qdisc.id
-    )?;
+    let ClassParams {
+        ceil,
+        rate,
+        priority,
+    } = params;
+    format!(
+        "tc class {verb} dev {} parent {} classid {} htb rate {rate} ceil {ceil} prio {priority} quantum 1500",
+        qdisc.device,
+        qdisc.class(qdisc.root_class_id),
+        qdisc.class(class_id)
+    )
+}
+
+pub fn build_remove_htb_class_command(qdisc: &QDisc, class_id: usize) -> String {
+    format!(
+        "tc class del dev {} classid {}",
+        qdisc.device,
+        qdisc.class(class_id)
+    )
+}
+
+/// The catch-all filter for the traffic that no program filter matched
+///
+/// It sends the traffic to the default class, or drops it in allowlist mode
+pub fn build_default_filter_command(qdisc: &QDisc, allowlist: bool) -> String {
+    format!(
+        "tc filter add dev {} parent {} prio 2 protocol ip u32 match u32 0 0 {}",
+        qdisc.device,
+        qdisc.handle(),
+        build_filter_action(qdisc, default_filter_action(qdisc, allowlist))
+    )
+}
 
-    Ok(class_id)
+pub fn default_filter_action(qdisc: &QDisc, allowlist: bool) -> FilterAction {
+    if allowlist {
+        FilterAction::Drop
+    } else {
+        FilterAction::Classify(qdisc.default_class_id)
+    }
+}
+
+pub fn tc_set_allowlist(transaction: &mut Transaction, qdisc: &QDisc, allowlist: bool) {
+    let remove = format!(
+        "tc filter del dev {} parent {} prio 2",
+        qdisc.device,
+        qdisc.handle()
+    );
+    transaction.add(
+        remove.clone(),
+        Some(build_default_filter_command(qdisc, !allowlist)),
+    );
+    transaction.add(build_default_filter_command(qdisc, allowlist), Some(remove));
 }
 
 pub fn build_global_rate_commands(
@@ -250,69 +371,144 @@ pub fn build_global_rate_commands(
         .unwrap_or_else(|| MAX_RATE.into());
     vec![
         format!(
-            "tc class change dev {} classid {}:{} htb rate {dl_rate}",
-            ingress.device, ingress.id, ingress.root_class_id
+            "tc class change dev {} classid {} htb rate {dl_rate}",
+            ingress.device,
+            ingress.class(ingress.root_class_id)
         ),
         format!(
-            "tc class change dev {} classid {}:{} htb rate 8 ceil {dl_rate}",
-            ingress.device, ingress.id, ingress.default_class_id
+            "tc class change dev {} classid {} htb rate 8 ceil {dl_rate}",
+            ingress.device,
+            ingress.class(ingress.default_class_id)
         ),
         format!(
-            "tc class change dev {} classid {}:{} htb rate {ul_rate}",
-            egress.device, egress.id, egress.root_class_id
+            "tc class change dev {} classid {} htb rate {ul_rate}",
+            egress.device,
+            egress.class(egress.root_class_id)
         ),
         format!(
-            "tc class change dev {} classid {}:{} htb rate 8 ceil {ul_rate}",
-            egress.device, egress.id, egress.default_class_id
+            "tc class change dev {} classid {} htb rate 8 ceil {ul_rate}",
+            egress.device,
+            egress.class(egress.default_class_id)
         ),
     ]
 }
 
-fn get_filter_ids(device: &str) -> Result<HashSet<String>> {
-    let output = run_out!("tc filter show dev {}", device)??;
+/// Handle of the u32 hash table holding the port filters of a qdisc
+pub const PORT_HASH_TABLE: usize = 1;
+/// The bucket of a port is its low byte
+const PORT_HASH_BUCKETS: usize = 256;
+
+/// The port matched by the filters of a qdisc
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PortField {
+    /// Outgoing traffic is filtered on the local source port
+    Source,
+    /// Incomming traffic is filtered on the local destination port
+    Destination,
+}
 
-    let mut ids = HashSet::new();
-    for line in output.lines() {
-        if !line.starts_with("filter") {
-            continue;
+impl PortField {
+    fn name(self) -> &'static str {
+        match self {
+            PortField::Source => "sport",
+            PortField::Destination => "dport",
         }
-        if let Some(hit) = line.split_whitespace().nth(11) {
-            // regex ([a-z0-9]+::[a-z0-9]+?)
-            if hit.split("::").count() == 2 {
-                ids.insert(hit.to_string());
-            }
+    }
+
+    /// Mask of the port low byte in the 32 bits of both ports, right after the ip header
+    fn hash_mask(self) -> &'static str {
+        match self {
+            PortField::Source => "0x00ff0000",
+            PortField::Destination => "0x000000ff",
         }
     }
-    Ok(ids)
 }
 
-pub fn tc_add_u32_filter(qdisc: &QDisc, predicate: String, class_id: usize) -> Result<String> {
-    let before = get_filter_ids(&qdisc.device)?;
-    run!(
-        "tc filter add dev {} protocol ip parent {}: prio 1 u32 {predicate} flowid {}:{class_id}",
-        qdisc.device,
-        qdisc.id,
-        qdisc.id,
-    )?;
-    let after = get_filter_ids(&qdisc.device)?;
-
-    let difference: Vec<_> = after.difference(&before).collect();
+/// Handle of the filter of a port: the bucket is the port low byte, the node its high byte
+///
+/// A port has one filter per qdisc so the handles never collide
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct FilterHandle {
+    bucket: usize,
+    node: usize,
+}
 
-    if let Some(diff) = difference.first() {
-        if difference.len() > 1 {
-            log::warn!("Parsed ambiguous filter handle: {:?}", difference);
+impl FilterHandle {
+    pub fn of_port(port: usize) -> Self {
+        Self {
+            bucket: port & 0xff,
+            // 0 isn't a valid node id
+            node: (port >> 8) + 1,
         }
-        Ok(diff.to_string())
-    } else {
-        panic!("tc_add_u32_filter paniced")
+    }
+
+    /// The filter handle of a port filter as printed by tc, `None` for the other filters
+    pub fn parse(fh: &str) -> Option<Self> {
+        let mut parts = fh.split(':');
+        let table = usize::from_str_radix(parts.next()?, 16).ok()?;
+        let bucket = usize::from_str_radix(parts.next()?, 16).ok()?;
+        let node = usize::from_str_radix(parts.next()?, 16).ok()?;
+        let handle = Self { bucket, node };
+        (table == PORT_HASH_TABLE && handle == Self::of_port(handle.port())).then_some(handle)
+    }
+
+    pub fn port(&self) -> usize {
+        ((self.node.saturating_sub(1)) << 8) | self.bucket
+    }
+
+    fn hash_table(&self) -> String {
+        format!("{PORT_HASH_TABLE:x}:{:x}:", self.bucket)
     }
 }
 
-pub fn tc_remove_u32_filter(qdisc: &QDisc, filter_id: String) -> Result<()> {
-    run!(
-        "tc filter del dev {} parent {}: handle {filter_id} prio 1 protocol ip u32",
+impl std::fmt::Display for FilterHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{PORT_HASH_TABLE:x}:{:x}:{:x}", self.bucket, self.node)
+    }
+}
+
+/// What happens to the packets matched by a filter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterAction {
+    /// Send them to the class with this id
+    Classify(usize),
+    /// Drop them
+    Drop,
+}
+
+fn build_filter_action(qdisc: &QDisc, action: FilterAction) -> String {
+    match action {
+        FilterAction::Classify(class_id) => format!("flowid {}", qdisc.class(class_id)),
+        FilterAction::Drop => "action drop".into(),
+    }
+}
+
+/// Filter the traffic of the port into the class of the action
+///
+/// `verb` is `add` for a new filter or `replace` to change the action of an existing one
+pub fn build_u32_filter_command(
+    verb: &str,
+    qdisc: &QDisc,
+    field: PortField,
+    port: usize,
+    action: FilterAction,
+) -> String {
+    let handle = FilterHandle::of_port(port);
+    format!(
+        "tc filter {verb} dev {} parent {} prio 1 protocol ip handle {handle} u32 ht {} match ip {} {port} 0xffff {}",
+        qdisc.device,
+        qdisc.handle(),
+        handle.hash_table(),
+        field.name(),
+        build_filter_action(qdisc, action),
+    )
+}
+
+pub fn build_remove_u32_filter_command(qdisc: &QDisc, handle: FilterHandle) -> String {
+    format!(
+        "tc filter del dev {} parent {} prio 1 handle {handle} protocol ip u32",
         qdisc.device,
-        qdisc.id,
+        qdisc.handle(),
     )
 }
 
@@ -439,6 +635,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn build_filter_action_classify_and_drop() {
+        let qdisc = QDisc {
+            device: "eth0".into(),
+            id: 2,
+            root_class_id: 1,
+            default_class_id: 2,
+        };
+
+        assert_eq!(
+            build_filter_action(&qdisc, FilterAction::Classify(5)),
+            "flowid 2:5"
+        );
+        assert_eq!(
+            build_filter_action(&qdisc, FilterAction::Drop),
+            "action drop"
+        );
+    }
+
+    #[test]
+    fn build_default_filter_command_drops_in_allowlist_mode() {
+        let qdisc = QDisc {
+            device: "eth0".into(),
+            id: 1,
+            root_class_id: 1,
+            default_class_id: 2,
+        };
+
+        assert_eq!(
+            build_default_filter_command(&qdisc, false),
+            "tc filter add dev eth0 parent 1: prio 2 protocol ip u32 match u32 0 0 flowid 1:2"
+        );
+        assert_eq!(
+            build_default_filter_command(&qdisc, true),
+            "tc filter add dev eth0 parent 1: prio 2 protocol ip u32 match u32 0 0 action drop"
+        );
+    }
+
     #[test]
     fn build_global_rate_commands_neither_set_defaults_both_to_max() {
         let ingress = QDisc {
@@ -461,4 +695,128 @@ mod tests {
             assert!(cmd.contains("4294967295"), "expected MAX_RATE in: {cmd}");
         }
     }
+
+    fn qdiscs(json: &str) -> Vec<QDiscState> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn check_existing_qdiscs_defaults() {
+        let noqueue =
+            qdiscs(r#"[{"kind":"noqueue","handle":"0:","root":true,"refcnt":2,"options":{}}]"#);
+        assert_eq!(
+            check_existing_qdiscs("eth0", &noqueue, false, false).unwrap(),
+            None
+        );
+        let mq = qdiscs(
+            r#"[{"kind":"mq","handle":"0:","root":true,"options":{}},
+            {"kind":"fq_codel","handle":"0:","parent":":2","options":{"limit":10240}},
+            {"kind":"fq_codel","handle":"0:","parent":":1","options":{"limit":10240}}]"#,
+        );
+        assert_eq!(
+            check_existing_qdiscs("eth0", &mq, false, false).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn check_existing_qdiscs_snapshot() {
+        let tbf = qdiscs(
+            r#"[{"kind":"tbf","handle":"8001:","root":true,"refcnt":2,"options":{"rate":125000}}]"#,
+        );
+        let root = check_existing_qdiscs("eth0", &tbf, true, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(root.kind, "tbf");
+        assert_eq!(root.handle, Handle::new(0x8001, 0));
+        assert_eq!(
+            root_qdisc_options("qdisc tbf 8001: root refcnt 2 rate 1Mbit burst 4Kb lat 400ms \n"),
+            "rate 1Mbit burst 4Kb lat 400ms"
+        );
+        assert_eq!(
+            root_qdisc_options("qdisc pfifo 1: root limit 50p"),
+            "limit 50p"
+        );
+    }
+
+    #[test]
+    fn check_existing_qdiscs_conflicts() {
+        let ingress = qdiscs(
+            r#"[{"kind":"noqueue","handle":"0:","root":true,"refcnt":2,"options":{}},
+            {"kind":"ingress","handle":"ffff:","parent":"ffff:fff1","options":{}}]"#,
+        );
+        assert!(check_existing_qdiscs("eth0", &ingress, false, false).is_err());
+        let htb = qdiscs(r#"[{"kind":"htb","handle":"1:","root":true,"options":{"r2q":10}}]"#);
+        assert!(check_existing_qdiscs("eth0", &htb, true, false).is_err());
+        let prio = qdiscs(
+            r#"[{"kind":"prio","handle":"1:","root":true,"options":{"bands":3}},
+            {"kind":"sfq","handle":"10:","parent":"1:1","options":{}}]"#,
+        );
+        assert!(check_existing_qdiscs("eth0", &prio, true, false).is_err());
+        let filtered =
+            qdiscs(r#"[{"kind":"prio","handle":"1:","root":true,"options":{"bands":3}}]"#);
+        assert!(check_existing_qdiscs("eth0", &filtered, true, true).is_err());
+    }
+
+    #[test]
+    fn class_ids_are_hex() {
+        let classes: Vec<Handle> = ["1:1", "1:2", "1:9", "1:a", "2:b"]
+            .iter()
+            .map(|c| c.parse().unwrap())
+            .collect();
+        let free = find_free_ids(classes.iter().filter(|c| c.major == 1).map(|c| c.minor));
+        assert_eq!(free, 3);
+        let classes: Vec<Handle> = (1..=10).map(|minor| Handle::new(1, minor)).collect();
+        let free = find_free_ids(classes.iter().map(|c| c.minor));
+        assert_eq!(
+            QDisc {
+                device: "eth0".into(),
+                id: 1,
+                root_class_id: 1,
+                default_class_id: 2
+            }
+            .class(free)
+            .to_string(),
+            "1:b"
+        );
+    }
+
+    #[test]
+    fn filter_handle_of_port() {
+        assert_eq!(FilterHandle::of_port(443).to_string(), "1:bb:2");
+        assert_eq!(FilterHandle::of_port(443).hash_table(), "1:bb:");
+        assert_eq!(FilterHandle::of_port(0).to_string(), "1:0:1");
+        assert_eq!(FilterHandle::of_port(65535).to_string(), "1:ff:100");
+        assert_ne!(FilterHandle::of_port(187), FilterHandle::of_port(443));
+        for port in [0, 443, 58470, 65535] {
+            let handle = FilterHandle::of_port(port);
+            assert_eq!(FilterHandle::parse(&handle.to_string()), Some(handle));
+            assert_eq!(handle.port(), port);
+        }
+        assert_eq!(FilterHandle::parse("1:"), None);
+        assert_eq!(FilterHandle::parse("800::800"), None);
+        assert_eq!(FilterHandle::parse("1:bb:0"), None);
+    }
+
+    #[test]
+    fn build_u32_filter_commands() {
+        let qdisc = QDisc {
+            device: "eth0".into(),
+            id: 10,
+            root_class_id: 1,
+            default_class_id: 2,
+        };
+        assert_eq!(
+            build_u32_filter_command("add", &qdisc, PortField::Source, 443, FilterAction::Classify(3)),
+            "tc filter add dev eth0 parent a: prio 1 protocol ip handle 1:bb:2 u32 ht 1:bb: match ip sport 443 0xffff flowid a:3"
+        );
+        assert_eq!(
+            build_remove_u32_filter_command(&qdisc, FilterHandle::of_port(443)),
+            "tc filter del dev eth0 parent a: prio 1 handle 1:bb:2 protocol ip u32"
+        );
+        assert_eq!(
+            build_htb_class_command("change", &qdisc, 3, &ClassParams::new(Some("1mbit".into()), None, None)),
+            "tc class change dev eth0 parent a:1 classid a:3 htb rate 8 ceil 1mbit prio 0 quantum 1500"
+        );
+    }
 }
@@ -0,0 +1,145 @@
+//! tc changes that are applied all together or not at all
+use crate::journal::{self, Entry};
+use crate::utils::run_batch;
+use crate::Result;
+
+/// A group of tc commands applied in one `tc -batch`
+///
+/// If a command fails the ones before it are undone, so the kernel is left as it was
+#[derive(Default)]
+pub struct Transaction {
+    steps: Vec<Step>,
+}
+
+struct Step {
+    /// tc command, like `tc qdisc add dev eth0 ...`
+    command: String,
+    /// tc command that reverts `command`
+    undo: Option<String>,
+    /// object created by `command`, recorded in the journal
+    entry: Option<Entry>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a tc command, `undo` reverts it if a later command fails
+    pub fn add(&mut self, command: String, undo: Option<String>) {
+        self.steps.push(Step {
+            command,
+            undo,
+            entry: None,
+        });
+    }
+
+    /// Add a tc command that creates an object that should be cleaned up after a crash
+    pub fn add_journaled(&mut self, command: String, undo: String, entry: Entry) {
+        self.steps.push(Step {
+            command,
+            undo: Some(undo),
+            entry: Some(entry),
+        });
+    }
+
+    #[cfg(test)]
+    pub fn commands(&self) -> Vec<String> {
+        self.steps.iter().map(|step| step.command.clone()).collect()
+    }
+
+    pub fn commit(self) -> Result<()> {
+        if self.steps.is_empty() {
+            return Ok(());
+        }
+        for entry in self.steps.iter().filter_map(|step| step.entry.clone()) {
+            journal::record(entry);
+        }
+
+        let commands: Vec<String> = self.steps.iter().map(|s| batch_line(&s.command)).collect();
+        let output = run_batch("tc", &commands)?;
+        if output.status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // without knowing which command failed, undo all of them
+        let failed = failed_command(&stderr).unwrap_or(self.steps.len() + 1);
+        let rollback = rollback_commands(&self.steps, failed);
+        log::warn!("Rolling back {} tc commands", rollback.len());
+        let rolled_back =
+            rollback.is_empty() || run_batch("tc -force", &rollback)?.status.success();
+        if rolled_back {
+            for entry in self.steps.into_iter().filter_map(|step| step.entry) {
+                journal::record_removal(entry);
+            }
+        }
+
+        let command = commands
+            .get(failed - 1)
+            .map_or(String::new(), |command| format!(" at `tc {command}`"));
+        Err(format!("tc batch failed{command}: {}", stderr.trim()).into())
+    }
+}
+
+/// `tc -batch` takes the commands without the `tc`
+fn batch_line(command: &str) -> String {
+    command
+        .strip_prefix("tc ")
+        .unwrap_or(command)
+        .trim()
+        .to_string()
+}
+
+/// `tc -batch` stops at the first failure and reports its line, starting at 1
+fn failed_command(stderr: &str) -> Option<usize> {
+    stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("Command failed -:"))
+        .and_then(|line| line.trim().parse().ok())
+        .filter(|line| *line > 0)
+}
+
+/// Undo the commands that ran before the failed one, newest first
+fn rollback_commands(steps: &[Step], failed: usize) -> Vec<String> {
+    steps[..(failed - 1).min(steps.len())]
+        .iter()
+        .rev()
+        .filter_map(|step| step.undo.as_deref())
+        .map(batch_line)
+        .collect()
+}
+
+#[test]
+fn test_failed_command() {
+    let stderr = "Cannot find device \"eth9\"\nCommand failed -:2\n";
+    assert_eq!(failed_command(stderr), Some(2));
+    assert_eq!(failed_command("Error: Invalid handle.\n"), None);
+}
+
+#[test]
+fn test_rollback_commands() {
+    let mut transaction = Transaction::new();
+    transaction.add(
+        "tc qdisc add dev eth0 handle ffff: ingress".into(),
+        Some("tc qdisc del dev eth0 ingress".into()),
+    );
+    transaction.add(
+        "tc class change dev eth0 classid 1:1 htb rate 8".into(),
+        None,
+    );
+    transaction.add(
+        "tc qdisc replace dev eth0 root handle 1: htb".into(),
+        Some("tc qdisc del dev eth0 root".into()),
+    );
+    transaction.add("tc filter add dev eth9 parent 1: prio 2".into(), None);
+
+    assert_eq!(
+        rollback_commands(&transaction.steps, 4),
+        vec!["qdisc del dev eth0 root", "qdisc del dev eth0 ingress"]
+    );
+    assert_eq!(
+        rollback_commands(&transaction.steps, 1),
+        Vec::<String>::new()
+    );
+}
@@ -1,6 +1,9 @@
+use crate::ipc::ProgramInfo;
 use crate::Result;
 use std::collections::HashMap;
-use std::process::{Command, Output};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc;
 
 #[macro_export]
 macro_rules! run {
@@ -34,39 +37,242 @@ pub fn run(v: String) -> Result<Output> {
     Ok(output)
 }
 
+/// Run `cmd -batch -` with the commands on stdin, one per line
+///
+/// The batch stops at the first failing command unless `-force` is part of `cmd`
+pub fn run_batch(cmd: &str, commands: &[String]) -> Result<Output> {
+    let mut args = cmd.split_whitespace();
+    let mut child = Command::new(args.next().expect("Tried to run an empty command"))
+        .args(args)
+        .args(["-batch", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    for command in commands {
+        writeln!(stdin, "{command}")?;
+    }
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if !output.stderr.is_empty() {
+        log::warn!(
+            "batch: {cmd:?} {commands:?} stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(output)
+}
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
 pub fn ifconfig() -> Result<Vec<Interface>> {
-    let raw_data = std::fs::read_to_string("/proc/net/dev")?;
+    let ifb_devices = parse_link_names(&run_out!("ip -o link show type ifb")??);
+    let mut addresses = parse_addresses(&run_out!("ip -o addr show")??);
 
-    //TODO: actually parse statue
-    raw_data
+    let mut interfaces = vec![];
+    for entry in std::fs::read_dir(SYS_CLASS_NET)? {
+        let dir = entry?.path();
+        let Some(name) = dir
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(ToString::to_string)
+        else {
+            continue;
+        };
+        let read = |attribute: &str| {
+            std::fs::read_to_string(dir.join(attribute))
+                .map(|value| value.trim().to_string())
+                .ok()
+        };
+        let kind = if ifb_devices.contains(&name) {
+            Kind::Ifb
+        } else if dir.join("wireless").exists() || dir.join("phy80211").exists() {
+            Kind::Wireless
+        } else if dir.join("tun_flags").exists() {
+            Kind::Tun
+        } else if dir.join("bridge").exists() {
+            Kind::Bridge
+        } else if read("type").as_deref() == Some(ARPHRD_LOOPBACK) {
+            Kind::Loopback
+        } else if dir.join("device").exists() {
+            Kind::Ethernet
+        } else {
+            Kind::Other
+        };
+        interfaces.push(Interface {
+            status: read("operstate")
+                .map(|s| Status::from(s.as_str()))
+                .unwrap_or(Status::Unknown),
+            admin_up: read("flags")
+                .and_then(|flags| usize::from_str_radix(flags.trim_start_matches("0x"), 16).ok())
+                .is_some_and(|flags| flags & IFF_UP != 0),
+            // reading the speed of a link that is down fails, and it is -1 when unknown
+            speed: read("speed").and_then(|speed| speed.parse().ok()),
+            mtu: read("mtu").and_then(|mtu| mtu.parse().ok()),
+            addresses: addresses.remove(&name).unwrap_or_default(),
+            kind,
+            name,
+        });
+    }
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(interfaces)
+}
+
+// from linux/if_arp.h and linux/if.h
+const ARPHRD_LOOPBACK: &str = "772";
+const IFF_UP: usize = 0x1;
+
+/// Names of the links listed by `ip -o link show`
+fn parse_link_names(output: &str) -> Vec<String> {
+    output
         .lines()
-        .skip(2)
-        .filter_map(|l| l.split(':').next())
-        .map(|name| {
-            Ok(Interface {
-                name: name.trim().to_string(),
-                status: Status::Down,
-            })
+        .filter_map(|line| {
+            let name = line.split_whitespace().nth(1)?.trim_end_matches(':');
+            // vlans and veths are shown as `name@parent`
+            Some(name.split('@').next()?.to_string())
         })
         .collect()
 }
 
-#[derive(PartialEq, Eq, Debug)]
+/// Interface name to addresses from `ip -o addr show`
+fn parse_addresses(output: &str) -> HashMap<String, Vec<String>> {
+    let mut addresses: HashMap<String, Vec<String>> = HashMap::new();
+    for line in output.lines() {
+        let mut fields = line.split_whitespace().skip(1);
+        let (Some(name), Some(_family), Some(address)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        addresses
+            .entry(name.to_string())
+            .or_default()
+            .push(address.to_string());
+    }
+    addresses
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub struct Interface {
     pub name: String,
-    status: Status,
+    pub status: Status,
+    /// The interface was brought up with `ip link set up`, its operational state can still be down
+    admin_up: bool,
+    pub kind: Kind,
+    /// Link speed in Mbit/s, unknown for most virtual and wireless devices
+    pub speed: Option<u32>,
+    pub mtu: Option<u32>,
+    /// Addresses with their prefix length, for example `192.168.1.5/24`
+    pub addresses: Vec<String>,
 }
 
 impl Interface {
     pub fn is_up(&self) -> bool {
-        self.status == Status::Up
+        self.admin_up
+    }
+
+    /// Short description shown next to the interface name
+    pub fn summary(&self) -> String {
+        let mut summary = format!("{:?}, {:?}", self.status, self.kind).to_lowercase();
+        if let Some(speed) = self.speed {
+            summary.push_str(&format!(", {speed} Mbit/s"));
+        }
+        if let Some(mtu) = self.mtu {
+            summary.push_str(&format!(", mtu {mtu}"));
+        }
+        for address in &self.addresses {
+            summary.push_str(&format!(", {address}"));
+        }
+        summary
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
-enum Status {
+/// Operational state, as in `/sys/class/net/<name>/operstate`
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Status {
     Up,
     Down,
+    Dormant,
+    LowerLayerDown,
+    NotPresent,
+    Testing,
+    /// Reported by devices that don't track their state, like tun and loopback devices
+    Unknown,
+}
+
+impl From<&str> for Status {
+    fn from(operstate: &str) -> Self {
+        match operstate {
+            "up" => Status::Up,
+            "down" => Status::Down,
+            "dormant" => Status::Dormant,
+            "lowerlayerdown" => Status::LowerLayerDown,
+            "notpresent" => Status::NotPresent,
+            "testing" => Status::Testing,
+            _ => Status::Unknown,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Kind {
+    Ethernet,
+    Wireless,
+    Tun,
+    Bridge,
+    Ifb,
+    Loopback,
+    Other,
+}
+
+/// The interface carrying the default route, the one with the lowest metric if there are several
+pub fn default_route_interface() -> Result<Option<String>> {
+    let routes = run_out!("ip route show default")??;
+    Ok(parse_default_route(&routes))
+}
+
+fn parse_default_route(routes: &str) -> Option<String> {
+    routes
+        .lines()
+        .filter_map(|route| {
+            let mut fields = route.split_whitespace();
+            let device = fields.clone().skip_while(|f| *f != "dev").nth(1)?;
+            let metric = fields
+                .by_ref()
+                .skip_while(|f| *f != "metric")
+                .nth(1)
+                .and_then(|m| m.parse().ok())
+                .unwrap_or(0usize);
+            Some((metric, device.to_string()))
+        })
+        .min()
+        .map(|(_, device)| device)
+}
+
+/// Watch the kernel routing table over rtnetlink (via `ip monitor route`)
+///
+/// A message is sent on the returned channel every time a route changes
+pub fn watch_routes() -> Result<mpsc::Receiver<()>> {
+    let mut monitor = Command::new("ip")
+        .args(["monitor", "route"])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdout = monitor
+        .stdout
+        .take()
+        .ok_or("Failed to read ip monitor output")?;
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            if line.is_err() || tx.send(()).is_err() {
+                break;
+            }
+        }
+        let _ = monitor.kill();
+        let _ = monitor.wait();
+    });
+    Ok(rx)
 }
 
 pub fn ss() -> Result<HashMap<String, Vec<Connection>>> {
@@ -82,10 +288,12 @@ pub fn ss() -> Result<HashMap<String, Vec<Connection>>> {
 fn ss_parse(row: &str, net_table: &mut HashMap<String, Vec<Connection>>) -> Option<()> {
     let is_ipv6 =
         |addr: &str| matches!(&addr[0..1], "[") && matches!(&addr[addr.len() - 1..addr.len()], "]");
-    let mut row = row.split_whitespace();
-    let laddr_lport = row.nth(3)?;
-    let raddr_rport = row.next()?;
-    let process = row.next()?;
+    let mut fields = row.split_whitespace();
+    let laddr_lport = fields.nth(3)?;
+    let raddr_rport = fields.next()?;
+    // the name is quoted and can have spaces, like `users:(("Web Content",pid=1996,fd=128))`
+    let (_, users) = row.split_once("users:((\"")?;
+    let (process, users) = users.split_once('"')?;
 
     let mut laddr_lport = laddr_lport.rsplitn(2, ':');
     let lport = laddr_lport.next()?;
@@ -101,13 +309,20 @@ fn ss_parse(row: &str, net_table: &mut HashMap<String, Vec<Connection>>) -> Opti
         raddr = raddr[1..raddr.len() - 1].to_string();
     }
 
-    let process = process.split('\"').nth(1)?.split('\"').next()?;
+    let pid = users
+        .split("pid=")
+        .nth(1)?
+        .split(',')
+        .next()?
+        .parse()
+        .ok()?;
     let net_entry: &mut Vec<Connection> = net_table.entry(process.to_string()).or_default();
     net_entry.push(Connection {
         laddr,
         lport: lport.parse().ok()?,
         raddr,
         rport: rport.parse().ok()?,
+        pid,
     });
 
     Some(())
@@ -119,6 +334,95 @@ pub struct Connection {
     pub lport: usize,
     pub raddr: String,
     pub rport: usize,
+    pub pid: u32,
+}
+
+/// The pids of the connections and what /proc says about the first one
+pub fn program_info(name: &str, connections: &[Connection]) -> ProgramInfo {
+    let mut pids: Vec<u32> = connections.iter().map(|c| c.pid).collect();
+    pids.sort_unstable();
+    pids.dedup();
+    let first = pids.first().copied();
+    let exe = first.and_then(|pid| std::fs::read_link(format!("/proc/{pid}/exe")).ok());
+    let cmdline = first.and_then(|pid| std::fs::read(format!("/proc/{pid}/cmdline")).ok());
+    ProgramInfo {
+        name: name.to_string(),
+        pids,
+        exe: exe.map(|exe| exe.to_string_lossy().into_owned()),
+        cmdline: cmdline
+            .map(|cmdline| parse_cmdline(&cmdline))
+            .filter(|cmdline| !cmdline.is_empty()),
+    }
+}
+
+/// The arguments in /proc/PID/cmdline are separated by nul bytes
+fn parse_cmdline(cmdline: &[u8]) -> String {
+    cmdline
+        .split(|&byte| byte == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[test]
+fn tifconfig() {
+    // every network namespace has a loopback device
+    let interfaces = ifconfig().unwrap();
+    let lo = interfaces.iter().find(|i| i.name == "lo").unwrap();
+    assert_eq!(lo.kind, Kind::Loopback);
+}
+
+#[test]
+fn test_parse_link_names() {
+    let output = "5: ifb0: <BROADCAST,NOARP,UP,LOWER_UP> mtu 1500 qdisc htb state UNKNOWN mode DEFAULT group default qlen 32\\    link/ether 3a:6e:7c:1b:9d:41 brd ff:ff:ff:ff:ff:ff
+7: veth1@if6: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue state UP mode DEFAULT group default qlen 1000\\    link/ether 02:42:ac:11:00:02 brd ff:ff:ff:ff:ff:ff link-netnsid 0
+";
+    assert_eq!(parse_link_names(output), vec!["ifb0", "veth1"]);
+}
+
+#[test]
+fn test_parse_addresses() {
+    let output = "1: lo    inet 127.0.0.1/8 scope host lo\\       valid_lft forever preferred_lft forever
+2: wlan0    inet 192.168.1.5/24 brd 192.168.1.255 scope global dynamic noprefixroute wlan0\\       valid_lft 86016sec preferred_lft 86016sec
+2: wlan0    inet6 fe80::1c2b:3aff:fe4d:5e6f/64 scope link noprefixroute \\       valid_lft forever preferred_lft forever
+";
+    let addresses = parse_addresses(output);
+    assert_eq!(addresses["lo"], vec!["127.0.0.1/8"]);
+    assert_eq!(
+        addresses["wlan0"],
+        vec!["192.168.1.5/24", "fe80::1c2b:3aff:fe4d:5e6f/64"]
+    );
+}
+
+#[test]
+fn test_status_from_operstate() {
+    assert_eq!(Status::from("up"), Status::Up);
+    assert_eq!(Status::from("lowerlayerdown"), Status::LowerLayerDown);
+    assert_eq!(Status::from("unknown"), Status::Unknown);
+}
+
+#[test]
+fn test_parse_default_route() {
+    assert_eq!(parse_default_route(""), None);
+    assert_eq!(
+        parse_default_route(
+            "default via 192.168.1.1 dev wlan0 proto dhcp src 192.168.1.5 metric 600\n"
+        ),
+        Some("wlan0".into())
+    );
+    assert_eq!(
+        parse_default_route(
+            "default via 192.168.1.1 dev wlan0 proto dhcp metric 600
+default via 10.0.0.1 dev eth0 proto dhcp metric 100
+"
+        ),
+        Some("eth0".into())
+    );
+    assert_eq!(
+        parse_default_route("default dev tun0 scope link"),
+        Some("tun0".into())
+    );
 }
 
 #[test]
@@ -136,6 +440,27 @@ fn test_ss_parse() {
                     lport: 5123,
                     raddr: "200.2000.200.1111".into(),
                     rport: 443,
+                    pid: 1996,
+                })
+            )]
+            .into_iter()
+            .collect()
+        )
+    }
+    let name_with_space = r#"tcp              0              0                        192.168.1.1:5124                     200.2000.200.1111:443            users:(("Web Content",pid=1997,fd=128))"#;
+    {
+        let mut process = HashMap::new();
+        ss_parse(name_with_space, &mut process);
+        assert_eq!(
+            process,
+            [(
+                "Web Content".to_string(),
+                vec!(Connection {
+                    laddr: "192.168.1.1".into(),
+                    lport: 5124,
+                    raddr: "200.2000.200.1111".into(),
+                    rport: 443,
+                    pid: 1997,
                 })
             )]
             .into_iter()
@@ -160,6 +485,7 @@ tcp              0      0                        [::1]:33586
                         lport: 9100,
                         raddr: "::2".into(),
                         rport: 33586,
+                        pid: 111305,
                     })
                 ),
                 (
@@ -169,6 +495,7 @@ tcp              0      0                        [::1]:33586
                         lport: 33586,
                         raddr: "::1".into(),
                         rport: 9100,
+                        pid: 261247,
                     })
                 )
             ]
@@ -177,3 +504,32 @@ tcp              0      0                        [::1]:33586
         )
     }
 }
+
+#[test]
+fn test_program_info() {
+    assert_eq!(parse_cmdline(b"firefox\0-P\0work\0"), "firefox -P work");
+    assert_eq!(parse_cmdline(b""), "");
+
+    let connection = |pid| Connection {
+        laddr: "127.0.0.1".into(),
+        lport: 5123,
+        raddr: "127.0.0.1".into(),
+        rport: 443,
+        pid,
+    };
+    let pid = std::process::id();
+    let info = program_info("test", &[connection(pid), connection(pid)]);
+    assert_eq!(info.pids, vec![pid]);
+    assert_eq!(
+        info.exe,
+        Some(std::env::current_exe().unwrap().display().to_string())
+    );
+    assert!(info.cmdline.is_some());
+    assert_eq!(
+        program_info("test", &[]),
+        ProgramInfo {
+            name: "test".into(),
+            ..Default::default()
+        }
+    );
+}
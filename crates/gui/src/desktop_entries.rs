@@ -0,0 +1,203 @@
+use eltrafico_client::ProgramInfo;
+use std::path::{Path, PathBuf};
+
+/// What the GUI shows of an application, from its .desktop file
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DesktopEntry {
+    /// the desktop file name without .desktop
+    pub id: String,
+    pub name: String,
+    /// an icon name of the theme or a path
+    pub icon: Option<String>,
+    /// the program that Exec or TryExec start, without its arguments
+    pub exec: Option<String>,
+    pub wm_class: Option<String>,
+}
+
+/// The applications installed in the XDG data dirs
+pub struct DesktopEntries(Vec<DesktopEntry>);
+
+impl DesktopEntries {
+    pub fn load() -> Self {
+        let mut entries: Vec<DesktopEntry> = vec![];
+        for dir in applications_dirs() {
+            let files = match std::fs::read_dir(&dir) {
+                Ok(files) => files,
+                Err(_) => continue,
+            };
+            for file in files.flatten() {
+                let path = file.path();
+                if path.extension().is_none_or(|ext| ext != "desktop") {
+                    continue;
+                }
+                let id = path.file_stem().unwrap().to_string_lossy().into_owned();
+                // the first dir wins, the user's entries override the system ones
+                if entries.iter().any(|entry| entry.id == id) {
+                    continue;
+                }
+                if let Some(entry) = std::fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|content| parse_desktop_entry(&id, &content))
+                {
+                    entries.push(entry);
+                }
+            }
+        }
+        DesktopEntries(entries)
+    }
+
+    /// Match the executable, then the desktop file name and the window class
+    pub fn find(&self, program: &ProgramInfo) -> Option<&DesktopEntry> {
+        let exe = program.exe.as_deref();
+        let exe_name = exe.map(file_name);
+        let names = [exe_name, Some(program.name.as_str())];
+        let names = names.iter().flatten();
+        self.0
+            .iter()
+            .find(|entry| {
+                entry.exec.as_deref().is_some_and(|exec| {
+                    exe == Some(exec) || (!exec.contains('/') && exe_name == Some(exec))
+                })
+            })
+            .or_else(|| {
+                self.0.iter().find(|entry| {
+                    names.clone().any(|name| {
+                        entry.id.eq_ignore_ascii_case(name)
+                            || entry
+                                .wm_class
+                                .as_deref()
+                                .is_some_and(|class| class.eq_ignore_ascii_case(name))
+                    })
+                })
+            })
+    }
+}
+
+/// `$XDG_DATA_HOME/applications` then every `$XDG_DATA_DIRS/applications`
+fn applications_dirs() -> Vec<PathBuf> {
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")));
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".into());
+    data_home
+        .into_iter()
+        .chain(data_dirs.split(':').map(PathBuf::from))
+        .map(|dir| dir.join("applications"))
+        .collect()
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// The keys of the [Desktop Entry] group, `None` for hidden entries and non applications
+fn parse_desktop_entry(id: &str, content: &str) -> Option<DesktopEntry> {
+    let mut in_group = false;
+    let mut entry = DesktopEntry {
+        id: id.to_string(),
+        name: id.to_string(),
+        icon: None,
+        exec: None,
+        wm_class: None,
+    };
+    let mut try_exec = None;
+    let mut application = false;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_group = line == "[Desktop Entry]";
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if in_group => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        match key {
+            "Type" => application = value == "Application",
+            "Hidden" if value == "true" => return None,
+            "Name" => entry.name = value.to_string(),
+            "Icon" if !value.is_empty() => entry.icon = Some(value.to_string()),
+            "Exec" => entry.exec = exec_program(value),
+            "TryExec" if !value.is_empty() => try_exec = Some(value.to_string()),
+            "StartupWMClass" => entry.wm_class = Some(value.to_string()),
+            _ => (),
+        }
+    }
+    entry.exec = try_exec.or(entry.exec);
+    application.then_some(entry)
+}
+
+/// The program of an Exec key: `env A=b "/opt/app/bin" %u` starts /opt/app/bin
+fn exec_program(exec: &str) -> Option<String> {
+    exec.split_whitespace()
+        .map(|word| word.trim_matches('"'))
+        .find(|word| *word != "env" && !word.contains('='))
+        .map(ToString::to_string)
+}
+
+#[test]
+fn test_desktop_entries() {
+    let firefox = parse_desktop_entry(
+        "firefox",
+        "[Desktop Entry]
+Name=Firefox
+Name[fr]=Firefox
+Exec=env MOZ_ENABLE_WAYLAND=1 firefox %u
+Icon=firefox
+Type=Application
+
+[Desktop Action new-window]
+Name=New Window
+Exec=firefox --new-window %u
+",
+    )
+    .unwrap();
+    assert_eq!(
+        firefox,
+        DesktopEntry {
+            id: "firefox".into(),
+            name: "Firefox".into(),
+            icon: Some("firefox".into()),
+            exec: Some("firefox".into()),
+            wm_class: None,
+        }
+    );
+    let code = parse_desktop_entry(
+        "code",
+        "[Desktop Entry]
+Name=Visual Studio Code
+Exec=\"/usr/share/code/code\" --unity-launch %F
+Icon=vscode
+StartupWMClass=Code
+Type=Application",
+    )
+    .unwrap();
+    assert!(
+        parse_desktop_entry("hidden", "[Desktop Entry]\nType=Application\nHidden=true").is_none()
+    );
+    assert!(parse_desktop_entry("link", "[Desktop Entry]\nType=Link").is_none());
+
+    let entries = DesktopEntries(vec![firefox, code]);
+    let program = |name: &str, exe: Option<&str>| ProgramInfo {
+        name: name.into(),
+        exe: exe.map(Into::into),
+        ..Default::default()
+    };
+    let find = |program| entries.find(&program).map(|entry| entry.name.clone());
+    assert_eq!(
+        find(program("Web Content", Some("/usr/lib/firefox/firefox"))),
+        Some("Firefox".into())
+    );
+    assert_eq!(
+        find(program("code", Some("/usr/share/code/code"))),
+        Some("Visual Studio Code".into())
+    );
+    assert_eq!(
+        find(program("Code", None)),
+        Some("Visual Studio Code".into())
+    );
+    assert_eq!(find(program("curl", Some("/usr/bin/curl"))), None);
+}
@@ -1,57 +1,111 @@
+mod chart;
+mod notifications;
+mod profile;
+mod program_list;
+mod tray;
 mod widget_builder;
+use crate::desktop_entries::DesktopEntries;
 use crate::netmonitor::netmonitor;
 use crate::run;
 use crate::utils::find_eltrafico_tc;
+use eltrafico_client::{BackendEvent, Client, Event, ProgramInfo};
 use gio::prelude::*;
 use gtk::prelude::*;
 use gtk::*;
+use notifications::{notify, Choice, Notifications};
+use profile::{Profile, RowSettings};
+use program_list::ProgramList;
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::io::Write;
-use std::io::{BufRead, BufReader};
-use std::process::{Command, Stdio};
 use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tray::{spawn_tray, TrayAction};
 use widget_builder::*;
 
+/// The first scan of eltrafico_tc reports every program that was already online,
+/// they arrive together and aren't worth a notification each
+const FIRST_SCAN: Duration = Duration::from_millis(500);
+
+fn apply_choice(row: &Row, choice: Choice) {
+    match choice {
+        Choice::Limit => row.set_active(true),
+        Choice::Block => row.set_blocked(true),
+        Choice::Ignore => (),
+    }
+}
+
+/// Stop the monitors and eltrafico_tc, it sends a Stop back so the gui can exit
+fn quit(client: &Client) {
+    // stop nethogs
+    let pid = String::from_utf8(run!("pidof nethogs").unwrap().stdout).unwrap();
+    if !pid.is_empty() {
+        run!("pkexec pkill nethogs").expect("Error stopping nethogs");
+    }
+    // stop bandwhich
+    let pid = String::from_utf8(run!("pidof bandwhich").unwrap().stdout).unwrap();
+    if !pid.is_empty() {
+        run!("pkexec pkill bandwhich").expect("Error stopping bandwhich");
+    }
+
+    // stop tc thread
+    client
+        .stop()
+        .expect("Error sending Stop message to eltrafico_tc");
+}
+
+/// The settings of every row, to save them or to restore them after a pause
+fn current_profile(global_row: &Row, program_list: &ProgramList) -> Profile {
+    Profile {
+        global: global_row.settings(),
+        programs: program_list
+            .rows()
+            .into_iter()
+            .map(|(name, row)| (name, row.settings()))
+            .collect(),
+    }
+}
+
 fn build_ui(application: &gtk::Application) {
+    // closing the window keeps it in the tray, launching again brings it back
+    if let Some(window) = application.windows().first() {
+        window.present();
+        return;
+    }
+
     // channels
     let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
     let tx_c = tx.clone();
 
     // spawn tc thread
     let eltrafico_tc = find_eltrafico_tc().expect("Cannot find eltrafico_tc binary");
-    let cmd = Command::new("pkexec")
-        .arg(eltrafico_tc)
-        .stdout(Stdio::piped())
-        .stdin(Stdio::piped())
-        // Debug
-        //.stderr(log)
-        .spawn()
-        .expect("Error spawning eltrafico_tc");
-
-    let stdin = Rc::new(RefCell::new(cmd.stdin));
-    let mut stdout = cmd.stdout;
-
-    // listen to tc thread stdout and send output to gui
+    let client = Client::spawn(eltrafico_tc).expect("Error spawning eltrafico_tc");
+    let events = client.subscribe();
+    let client = Rc::new(client);
+
+    // forward the events of eltrafico_tc to the gui
     std::thread::spawn(move || {
-        let mut tmp = String::new();
-        let mut stdout = BufReader::new(stdout.as_mut().unwrap());
-
-        loop {
-            stdout
-                .read_line(&mut tmp)
-                .expect("Error reading message from eltrafico_tc");
-            if tmp.is_empty() || tmp.trim() == "Stop" {
-                tx_c.send(UpdateGuiMessage::Stop)
-                    .expect("Error sending msg to gui thread");
-            } else {
-                tx_c.send(UpdateGuiMessage::ProgramEntry(tmp.trim().to_string()))
-                    .expect("Error sending msg to gui thread");
-            }
-            tmp.clear();
+        for event in events {
+            let message = match event {
+                Event::Backend(BackendEvent::ProgramEntry(program)) => {
+                    UpdateGuiMessage::ProgramEntry(program)
+                }
+                Event::Backend(BackendEvent::ProgramExit(program)) => {
+                    UpdateGuiMessage::ProgramExit(program)
+                }
+                Event::Backend(BackendEvent::InterfaceChanged(interface)) => {
+                    UpdateGuiMessage::InterfaceChanged(interface)
+                }
+                Event::Stopped => UpdateGuiMessage::Stop,
+                Event::Died => UpdateGuiMessage::Died,
+            };
+            tx_c.send(message).expect("Error sending msg to gui thread");
         }
     });
 
+    let (tray, in_tray) = spawn_tray(tx.clone());
+    let tx_notify = tx.clone();
+
     // start the netmonitor thread
     netmonitor(tx).expect("Error starting the netmonitor thread");
 
@@ -70,38 +124,52 @@ fn build_ui(application: &gtk::Application) {
     }
 
     let main_box = Box::new(Orientation::Vertical, 10);
-    let interface_row = create_interface_row(stdin.clone());
-    let global_bar = create_row(Some("global"), stdin.clone(), true);
-    let app_box = Box::new(Orientation::Vertical, 10);
+    let (interface_row, followed_interface) = create_interface_row(client.clone());
+    let global_row = create_row(Some("global"), client.clone(), true);
 
-    // make the app box vertically scrollable
-    let scrolled_box: ScrolledWindow = ScrolledWindow::new::<Adjustment, Adjustment>(None, None);
-    scrolled_box.set_hscrollbar_policy(PolicyType::Never);
-    scrolled_box.add(&app_box);
+    // ask what to do with programs that start using the network
+    let notifications = Notifications::load().unwrap_or_else(|e| {
+        eprintln!("Error loading the notification settings: {}", e);
+        Notifications::default()
+    });
+    let notify_btn = CheckButton::with_label("Notify new programs");
+    notify_btn.set_active(notifications.enabled);
+    let notifications = Rc::new(RefCell::new(notifications));
+    let notifications_c = notifications.clone();
+    notify_btn.connect_toggled(move |btn| {
+        let mut notifications = notifications_c.borrow_mut();
+        notifications.enabled = btn.is_active();
+        if let Err(e) = notifications.save() {
+            eprintln!("Error saving the notification settings: {}", e);
+        }
+    });
+    interface_row.add(&notify_btn);
+    let mut first_entry: Option<Instant> = None;
+    let tray_c = tray.clone();
+    global_row.connect_active_toggled(move |active| {
+        tray_c.update(|tray| tray.global_limit = active);
+    });
+    let program_list = ProgramList::new();
+    // the names and icons of the programs
+    let desktop_entries = DesktopEntries::load();
+    // settings of a profile for programs that didn't show up yet
+    let mut pending: HashMap<String, RowSettings> = HashMap::new();
+    // the settings to restore when the pause ends
+    let mut paused: Option<Profile> = None;
 
     main_box.add(&interface_row);
-    main_box.add(&global_bar);
-    main_box.pack_end(&scrolled_box, true, true, 10);
+    main_box.add(&global_row.widget);
+    main_box.pack_end(&program_list.widget, true, true, 10);
     window.add(&main_box);
 
-    // Cleanup at exit
-    let stdin_c = stdin.clone();
-    window.connect_delete_event(move |_, _| {
-        // stop nethogs
-        let pid = String::from_utf8(run!("pidof nethogs").unwrap().stdout).unwrap();
-        if !pid.is_empty() {
-            run!("pkexec pkill nethogs").expect("Error stopping nethogs");
+    // Cleanup at exit, unless the tray keeps the limits going
+    let client_c = client.clone();
+    window.connect_delete_event(move |window, _| {
+        if in_tray.load(Ordering::Acquire) {
+            window.hide();
+        } else {
+            quit(&client_c);
         }
-        // stop bandwhich
-        let pid = String::from_utf8(run!("pidof bandwhich").unwrap().stdout).unwrap();
-        if !pid.is_empty() {
-            run!("pkexec pkill bandwhich").expect("Error stopping bandwhich");
-        }
-
-        // stop tc thread
-        // tc will send a STOP msg back to the main thread so it can exit
-        writeln!(stdin_c.borrow_mut().as_mut().unwrap(), "{}", Message::Stop)
-            .expect("Error sending Stop message to eltrafico_tc");
         Inhibit(true)
     });
 
@@ -115,24 +183,107 @@ fn build_ui(application: &gtk::Application) {
     rx.attach(None, move |message| {
         match message {
             UpdateGuiMessage::CurrentProgramSpeed(prgoram_current_speed) => {
-                update_gui_program_speed(app_box.clone(), prgoram_current_speed);
+                program_list.update_speeds(prgoram_current_speed);
             }
             UpdateGuiMessage::CurrentGlobalSpeed(global_speed) => {
-                update_gui_global_speed(global_bar.clone(), global_speed);
+                global_row.set_speed(global_speed);
+                tray.update(|tray| tray.global_speed = global_speed);
             }
             UpdateGuiMessage::ProgramEntry(program) => {
-                if !program.is_empty() {
-                    let stdin = stdin.clone();
-                    let program = program
-                        .split("ProgramEntry: ")
-                        .nth(1)
-                        .unwrap_or_else(|| panic!("Malformated message: {}", program));
-                    let app_bar = create_row(Some(program), stdin, false);
-                    app_box.add(&app_bar);
-                    app_box.show_all();
+                // a program that exited keeps its row and gets it back when it returns
+                let entry = desktop_entries.find(&program);
+                if let Some(row) = program_list.get(&program.name) {
+                    row.set_program(&program, entry);
+                    program_list.set_idle(&program.name, false);
+                } else {
+                    let row = create_row(Some(&program.name), client.clone(), false);
+                    row.set_program(&program, entry);
+                    let first_scan = *first_entry.get_or_insert_with(Instant::now);
+                    let choice = notifications.borrow().choices.get(&program.name).copied();
+                    if let Some(settings) = pending.remove(&program.name) {
+                        row.apply(&settings);
+                    } else if let Some(choice) = choice {
+                        apply_choice(&row, choice);
+                    } else if notifications.borrow().enabled && first_scan.elapsed() > FIRST_SCAN {
+                        notify(
+                            &program,
+                            &entry.map_or(program.name.clone(), |entry| entry.name.clone()),
+                            entry.and_then(|entry| entry.icon.as_deref()),
+                            tx_notify.clone(),
+                        );
+                    }
+                    program_list.add(program.name, row);
                 }
             }
+            UpdateGuiMessage::ProgramExit(program) => {
+                program_list.set_idle(&program, true);
+            }
+            UpdateGuiMessage::NewProgramChoice(program, choice) => {
+                if let Some(row) = program_list.get(&program) {
+                    apply_choice(&row, choice);
+                }
+                let mut notifications = notifications.borrow_mut();
+                notifications.choices.insert(program, choice);
+                if let Err(e) = notifications.save() {
+                    eprintln!("Error saving the notification settings: {}", e);
+                }
+            }
+            UpdateGuiMessage::InterfaceChanged(interface) => {
+                followed_interface.set_text(&format!("Following: {}", interface));
+            }
+            UpdateGuiMessage::Tray(action) => match action {
+                TrayAction::Show => window.present(),
+                TrayAction::ToggleGlobal => global_row.set_active(!global_row.is_active()),
+                TrayAction::ApplyProfile => match Profile::load() {
+                    Ok(profile) => {
+                        paused = None;
+                        tray.update(|tray| tray.paused = false);
+                        global_row.apply(&profile.global);
+                        for (name, settings) in profile.programs {
+                            match program_list.get(&name) {
+                                Some(row) => row.apply(&settings),
+                                None => {
+                                    pending.insert(name, settings);
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => eprintln!("Error loading the profile: {}", e),
+                },
+                TrayAction::SaveProfile => {
+                    // while paused the limits are the ones before the pause
+                    let saved = match &paused {
+                        Some(profile) => profile.save(),
+                        None => current_profile(&global_row, &program_list).save(),
+                    };
+                    if let Err(e) = saved {
+                        eprintln!("Error saving the profile: {}", e);
+                    }
+                }
+                TrayAction::TogglePause => {
+                    if let Some(profile) = paused.take() {
+                        global_row.apply(&profile.global);
+                        for (name, settings) in &profile.programs {
+                            if let Some(row) = program_list.get(name) {
+                                row.apply(settings);
+                            }
+                        }
+                    } else {
+                        paused = Some(current_profile(&global_row, &program_list));
+                        global_row.pause();
+                        for (_, row) in program_list.rows() {
+                            row.pause();
+                        }
+                    }
+                    tray.update(|tray| tray.paused = paused.is_some());
+                }
+                TrayAction::Quit => quit(&client),
+            },
             UpdateGuiMessage::Stop => std::process::exit(0),
+            UpdateGuiMessage::Died => {
+                eprintln!("Error: eltrafico_tc exited unexpectedly, nothing is limited anymore");
+                std::process::exit(1);
+            }
         }
 
         glib::Continue(true)
@@ -157,87 +308,17 @@ pub fn run() {
     application.run();
 }
 
-#[derive(Eq, PartialEq)]
-pub enum Message {
-    Stop,
-    Interface(String),
-    Global((Option<String>, Option<String>)),
-    Program(
-        (
-            String,
-            (
-                Option<String>,
-                Option<String>,
-                Option<String>,
-                Option<String>,
-            ),
-        ),
-    ),
-}
-
-use std::fmt;
-impl fmt::Display for Message {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use Message::*;
-        match self {
-            Stop => write!(f, "Stop"),
-            Interface(interface) => write!(f, "Interface: {}", interface),
-            Global((up, down)) => {
-                let mut msg = "Global: ".to_string();
-                if let Some(up) = up {
-                    msg.push_str(up);
-                    msg.push(' ');
-                } else {
-                    msg.push_str("None ");
-                }
-                if let Some(down) = down {
-                    msg.push_str(down);
-                    msg.push(' ');
-                } else {
-                    msg.push_str("None ");
-                }
-                write!(f, "{}", msg)
-            }
-            Program((program, (down, up, down_min, up_min))) => {
-                let mut msg = "Program: ".to_string();
-
-                msg.push_str(program);
-                msg.push(' ');
-                if let Some(down) = down {
-                    msg.push_str(down);
-                    msg.push(' ');
-                } else {
-                    msg.push_str("None ");
-                }
-                if let Some(up) = up {
-                    msg.push_str(up);
-                    msg.push(' ');
-                } else {
-                    msg.push_str("None ");
-                }
-
-                if let Some(down_min) = down_min {
-                    msg.push_str(down_min);
-                    msg.push(' ');
-                } else {
-                    msg.push_str("None ");
-                }
-                if let Some(up_min) = up_min {
-                    msg.push_str(up_min);
-                    msg.push(' ');
-                } else {
-                    msg.push_str("None ");
-                }
-                write!(f, "{}", msg)
-            }
-        }
-    }
-}
-
 #[derive(Debug)]
 pub enum UpdateGuiMessage {
     Stop,
-    ProgramEntry(String),
+    /// eltrafico_tc went away without a Stop
+    Died,
+    ProgramEntry(ProgramInfo),
+    ProgramExit(String),
+    InterfaceChanged(String),
     CurrentProgramSpeed(HashMap<String, (f32, f32)>),
     CurrentGlobalSpeed((f32, f32)),
+    Tray(TrayAction),
+    /// The answer to the notification of a new program
+    NewProgramChoice(String, Choice),
 }
@@ -0,0 +1,132 @@
+use gtk::cairo;
+use gtk::prelude::*;
+use gtk::{gdk, DrawingArea};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// How many speeds a chart remembers, the netmonitor sends one about every second
+const HISTORY_LEN: usize = 60;
+const SMALL: (i32, i32) = (120, 30);
+const EXPANDED: (i32, i32) = (480, 120);
+
+const DOWN_COLOR: (f64, f64, f64) = (0.2, 0.4, 0.9);
+const UP_COLOR: (f64, f64, f64) = (0.9, 0.5, 0.1);
+
+/// The limits of a row in KB/sec as (up, down), None when that direction isn't limited
+pub type Limits = (Option<f32>, Option<f32>);
+
+/// A sparkline of the recent speeds of a row, clicking it expands it
+#[derive(Clone)]
+pub struct Chart {
+    area: DrawingArea,
+    /// (up, down) in KB/sec, oldest first
+    history: Rc<RefCell<VecDeque<(f32, f32)>>>,
+}
+
+impl Chart {
+    /// `limits` is asked on every redraw so the chart follows the row settings
+    pub fn new(limits: impl Fn() -> Limits + 'static) -> Self {
+        let area = DrawingArea::new();
+        area.set_size_request(SMALL.0, SMALL.1);
+        area.set_tooltip_text(Some(
+            "Down in blue, up in orange, limits dashed. Click to expand",
+        ));
+        area.add_events(gdk::EventMask::BUTTON_PRESS_MASK);
+        area.connect_button_press_event(|area, _| {
+            let size = if area.size_request() == SMALL {
+                EXPANDED
+            } else {
+                SMALL
+            };
+            area.set_size_request(size.0, size.1);
+            Inhibit(true)
+        });
+
+        let history: Rc<RefCell<VecDeque<(f32, f32)>>> = Default::default();
+        let history_c = history.clone();
+        area.connect_draw(move |area, cr| {
+            let size = (
+                area.allocated_width() as f64,
+                area.allocated_height() as f64,
+            );
+            if let Err(e) = draw(cr, size, &history_c.borrow(), limits()) {
+                eprintln!("Error drawing the speed chart: {}", e);
+            }
+            Inhibit(false)
+        });
+        Chart { area, history }
+    }
+
+    pub fn widget(&self) -> &DrawingArea {
+        &self.area
+    }
+
+    pub fn push(&self, speed: (f32, f32)) {
+        let mut history = self.history.borrow_mut();
+        if history.len() == HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(speed);
+        self.area.queue_draw();
+    }
+}
+
+fn draw(
+    cr: &cairo::Context,
+    (width, height): (f64, f64),
+    history: &VecDeque<(f32, f32)>,
+    (up_limit, down_limit): Limits,
+) -> Result<(), cairo::Error> {
+    cr.set_source_rgba(0.5, 0.5, 0.5, 0.1);
+    cr.rectangle(0., 0., width, height);
+    cr.fill()?;
+
+    // keep the limits in view even when the program is far below them
+    let max = history
+        .iter()
+        .flat_map(|&(up, down)| [up, down])
+        .chain(up_limit)
+        .chain(down_limit)
+        .fold(1., f32::max) as f64
+        * 1.1;
+    let y = |speed: f32| height - speed as f64 / max * height;
+    let step = width / (HISTORY_LEN - 1) as f64;
+    // the newest speed is on the right edge
+    let start = width - (history.len() as f64 - 1.) * step;
+
+    cr.set_line_width(1.5);
+    let x = |i: usize| start + i as f64 * step;
+    let downs = history.iter().map(|&(_, down)| down);
+    draw_line(cr, DOWN_COLOR, downs.enumerate(), down_limit, width, &x, &y)?;
+    let ups = history.iter().map(|&(up, _)| up);
+    draw_line(cr, UP_COLOR, ups.enumerate(), up_limit, width, &x, &y)
+}
+
+/// The speeds as a line and the limit as a dashed line across the chart
+fn draw_line(
+    cr: &cairo::Context,
+    (r, g, b): (f64, f64, f64),
+    speeds: impl Iterator<Item = (usize, f32)>,
+    limit: Option<f32>,
+    width: f64,
+    x: &dyn Fn(usize) -> f64,
+    y: &dyn Fn(f32) -> f64,
+) -> Result<(), cairo::Error> {
+    cr.set_source_rgba(r, g, b, 1.);
+    cr.set_dash(&[], 0.);
+    cr.new_path();
+    for (i, speed) in speeds {
+        cr.line_to(x(i), y(speed));
+    }
+    cr.stroke()?;
+
+    if let Some(limit) = limit {
+        cr.set_source_rgba(r, g, b, 0.6);
+        cr.set_dash(&[4., 4.], 0.);
+        cr.move_to(0., y(limit));
+        cr.line_to(width, y(limit));
+        cr.stroke()?;
+    }
+    Ok(())
+}
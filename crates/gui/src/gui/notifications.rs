@@ -0,0 +1,110 @@
+use super::UpdateGuiMessage;
+use crate::utils::config_dir;
+use crate::CatchAll;
+use eltrafico_client::ProgramInfo;
+use notify_rust::{Notification, Timeout};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// How long a notification stays up, its thread waits for an answer till it closes
+const TIMEOUT: Timeout = Timeout::Milliseconds(60_000);
+
+/// What to do with a new program, asked in a notification and remembered for next time
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Choice {
+    /// Activate the row with its default limits
+    Limit,
+    Block,
+    Ignore,
+}
+
+/// Whether to ask about new programs and the answers so far
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Notifications {
+    pub enabled: bool,
+    pub choices: HashMap<String, Choice>,
+}
+
+impl Notifications {
+    /// `$XDG_CONFIG_HOME/eltrafico/notifications.json`
+    fn path() -> Option<PathBuf> {
+        Some(config_dir()?.join("notifications.json"))
+    }
+
+    /// Disabled without remembered choices until something is saved
+    pub fn load() -> CatchAll<Notifications> {
+        let path = Notifications::path().ok_or("Can't find the config directory")?;
+        if !path.exists() {
+            return Ok(Notifications::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self) -> CatchAll<()> {
+        let path = Notifications::path().ok_or("Can't find the config directory")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Ask what to do with the program, the answer comes back as a `NewProgramChoice`
+///
+/// Closing the notification or letting it expire decides nothing, the program is asked about
+/// again next time
+pub fn notify(
+    program: &ProgramInfo,
+    title: &str,
+    icon: Option<&str>,
+    tx: glib::Sender<UpdateGuiMessage>,
+) {
+    let pids: Vec<String> = program.pids.iter().map(ToString::to_string).collect();
+    let body = if title == program.name {
+        format!(
+            "{} (pid {}) started using the network",
+            title,
+            pids.join(", ")
+        )
+    } else {
+        format!(
+            "{} ({}, pid {}) started using the network",
+            title,
+            program.name,
+            pids.join(", ")
+        )
+    };
+    let mut notification = Notification::new();
+    notification
+        .appname("ElTrafico")
+        .summary("New program on the network")
+        .body(&body)
+        .icon(icon.unwrap_or("network-transmit-receive"))
+        .action("limit", "Limit to default")
+        .action("block", "Block")
+        .action("ignore", "Ignore")
+        .timeout(TIMEOUT);
+
+    let name = program.name.clone();
+    std::thread::spawn(move || {
+        let handle = match notification.show() {
+            Ok(handle) => handle,
+            Err(e) => {
+                eprintln!("Error showing the notification: {}", e);
+                return;
+            }
+        };
+        handle.wait_for_action(|action| {
+            let choice = match action {
+                "limit" => Choice::Limit,
+                "block" => Choice::Block,
+                "ignore" => Choice::Ignore,
+                _ => return,
+            };
+            tx.send(UpdateGuiMessage::NewProgramChoice(name, choice))
+                .expect("Error sending msg to gui thread");
+        });
+    });
+}
@@ -0,0 +1,46 @@
+use crate::utils::config_dir;
+use crate::CatchAll;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// What a row shows, enough to restore it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RowSettings {
+    /// down, up, down min and up min as a value and its unit
+    pub limits: [(f64, String); 4],
+    pub active: bool,
+    pub blocked: bool,
+}
+
+/// The settings of the global row and of the program rows
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Profile {
+    pub global: RowSettings,
+    pub programs: HashMap<String, RowSettings>,
+}
+
+impl Profile {
+    /// `$XDG_CONFIG_HOME/eltrafico/profile.json`
+    pub fn path() -> Option<PathBuf> {
+        Some(config_dir()?.join("profile.json"))
+    }
+
+    pub fn exists() -> bool {
+        Profile::path().is_some_and(|path| path.exists())
+    }
+
+    pub fn load() -> CatchAll<Profile> {
+        let path = Profile::path().ok_or("Can't find the config directory")?;
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self) -> CatchAll<()> {
+        let path = Profile::path().ok_or("Can't find the config directory")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
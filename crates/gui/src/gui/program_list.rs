@@ -0,0 +1,152 @@
+use super::widget_builder::Row;
+use glib::clone;
+use gtk::prelude::*;
+use gtk::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+struct Entry {
+    row: Row,
+    /// Position in the order the programs showed up
+    index: usize,
+    /// Down plus up in KB/sec
+    usage: f32,
+    idle: bool,
+}
+
+/// The program rows keyed by program, with the controls to sort and filter them
+pub struct ProgramList {
+    pub widget: Box,
+    app_box: Box,
+    search: SearchEntry,
+    sort: ComboBoxText,
+    hide_idle: CheckButton,
+    entries: RefCell<HashMap<String, Entry>>,
+}
+
+impl ProgramList {
+    pub fn new() -> Rc<ProgramList> {
+        let search = SearchEntry::new();
+        search.set_placeholder_text(Some("Search programs"));
+        let sort = ComboBoxText::new();
+        sort.append(Some("appearance"), "First seen");
+        sort.append(Some("name"), "Name");
+        sort.append(Some("usage"), "Current usage");
+        sort.set_active_id(Some("appearance"));
+        let hide_idle = CheckButton::with_label("Hide idle programs");
+
+        let controls = Box::new(Orientation::Horizontal, 10);
+        controls.add(&search);
+        controls.add(&Label::new(Some("Sort by: ")));
+        controls.add(&sort);
+        controls.add(&hide_idle);
+
+        let app_box = Box::new(Orientation::Vertical, 10);
+        // make the app box vertically scrollable
+        let scrolled_box: ScrolledWindow =
+            ScrolledWindow::new::<Adjustment, Adjustment>(None, None);
+        scrolled_box.set_hscrollbar_policy(PolicyType::Never);
+        scrolled_box.add(&app_box);
+
+        let widget = Box::new(Orientation::Vertical, 10);
+        widget.add(&controls);
+        widget.pack_start(&scrolled_box, true, true, 0);
+
+        let list = Rc::new(ProgramList {
+            widget,
+            app_box,
+            search,
+            sort,
+            hide_idle,
+            entries: Default::default(),
+        });
+        list.search
+            .connect_search_changed(clone!(@weak list => move |_| list.refresh()));
+        list.sort
+            .connect_changed(clone!(@weak list => move |_| list.refresh()));
+        list.hide_idle
+            .connect_toggled(clone!(@weak list => move |_| list.refresh()));
+        list
+    }
+
+    pub fn get(&self, name: &str) -> Option<Row> {
+        self.entries
+            .borrow()
+            .get(name)
+            .map(|entry| entry.row.clone())
+    }
+
+    pub fn rows(&self) -> Vec<(String, Row)> {
+        self.entries
+            .borrow()
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.row.clone()))
+            .collect()
+    }
+
+    pub fn add(&self, name: String, row: Row) {
+        self.app_box.add(&row.widget);
+        row.widget.show_all();
+        let index = self.entries.borrow().len();
+        self.entries.borrow_mut().insert(
+            name,
+            Entry {
+                row,
+                index,
+                usage: 0.,
+                idle: false,
+            },
+        );
+        self.refresh();
+    }
+
+    /// Grey out the row of a program that exited, its limits are still applied if it returns
+    pub fn set_idle(&self, name: &str, idle: bool) {
+        if let Some(entry) = self.entries.borrow_mut().get_mut(name) {
+            entry.idle = idle;
+            entry.row.widget.set_opacity(if idle { 0.5 } else { 1. });
+        }
+        self.refresh();
+    }
+
+    /// The programs missing from the speeds aren't active network wise anymore
+    pub fn update_speeds(&self, speeds: HashMap<String, (f32, f32)>) {
+        for (name, entry) in self.entries.borrow_mut().iter_mut() {
+            let speed = speeds.get(name).copied().unwrap_or_default();
+            entry.row.set_speed(speed);
+            entry.usage = speed.0 + speed.1;
+        }
+        if self.sort.active_id().as_deref() == Some("usage") {
+            self.refresh();
+        }
+    }
+
+    /// Order and filter the rows following the controls
+    fn refresh(&self) {
+        let entries = self.entries.borrow();
+        let mut names: Vec<&String> = entries.keys().collect();
+        match self.sort.active_id().as_deref() {
+            Some("name") => names.sort_by_key(|name| entries[*name].row.title().to_lowercase()),
+            Some("usage") => names.sort_by(|a, b| {
+                entries[*b]
+                    .usage
+                    .total_cmp(&entries[*a].usage)
+                    .then(entries[*a].index.cmp(&entries[*b].index))
+            }),
+            _ => names.sort_by_key(|name| entries[*name].index),
+        }
+
+        let search = self.search.text().to_lowercase();
+        let hide_idle = self.hide_idle.is_active();
+        for (position, name) in names.into_iter().enumerate() {
+            let entry = &entries[name];
+            self.app_box
+                .reorder_child(&entry.row.widget, position as i32);
+            // the title has the application name and the name from ss
+            let matches = entry.row.title().to_lowercase().contains(&search);
+            let hidden = (hide_idle && entry.idle) || !matches;
+            entry.row.widget.set_visible(!hidden);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use super::profile::Profile;
+use super::UpdateGuiMessage;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum TrayAction {
+    Show,
+    ToggleGlobal,
+    ApplyProfile,
+    SaveProfile,
+    TogglePause,
+    Quit,
+}
+
+/// The StatusNotifierItem, its actions are handled by the gui thread
+pub struct Tray {
+    tx: glib::Sender<UpdateGuiMessage>,
+    /// (up, down) in KB/sec
+    pub global_speed: (f32, f32),
+    pub global_limit: bool,
+    pub paused: bool,
+    /// A tray host shows the icon, otherwise closing the window can't hide it
+    shown: Arc<AtomicBool>,
+}
+
+impl Tray {
+    fn send(&self, action: TrayAction) {
+        self.tx
+            .send(UpdateGuiMessage::Tray(action))
+            .expect("Error sending msg to gui thread");
+    }
+}
+
+impl ksni::Tray for Tray {
+    fn id(&self) -> String {
+        "eltrafico".into()
+    }
+    fn title(&self) -> String {
+        "ElTrafico".into()
+    }
+    fn icon_name(&self) -> String {
+        "network-transmit-receive".into()
+    }
+    fn tool_tip(&self) -> ksni::ToolTip {
+        let mut description = format!(
+            "Down: {:.2} KB/sec Up: {:.2} KB/sec",
+            self.global_speed.1, self.global_speed.0
+        );
+        if self.paused {
+            description.push_str("\nAll limits are paused");
+        }
+        ksni::ToolTip {
+            title: "ElTrafico".into(),
+            description,
+            ..Default::default()
+        }
+    }
+    fn activate(&mut self, _x: i32, _y: i32) {
+        self.send(TrayAction::Show);
+    }
+    fn menu(&self) -> Vec<ksni::MenuItem<Self>> {
+        use ksni::menu::*;
+        vec![
+            StandardItem {
+                label: format!(
+                    "Down: {:.2} KB/sec Up: {:.2} KB/sec",
+                    self.global_speed.1, self.global_speed.0
+                ),
+                enabled: false,
+                ..Default::default()
+            }
+            .into(),
+            StandardItem {
+                label: "Show".into(),
+                activate: Box::new(|tray: &mut Self| tray.send(TrayAction::Show)),
+                ..Default::default()
+            }
+            .into(),
+            MenuItem::Separator,
+            CheckmarkItem {
+                label: "Global limit".into(),
+                checked: self.global_limit,
+                activate: Box::new(|tray: &mut Self| tray.send(TrayAction::ToggleGlobal)),
+                ..Default::default()
+            }
+            .into(),
+            CheckmarkItem {
+                label: "Pause all limits".into(),
+                checked: self.paused,
+                activate: Box::new(|tray: &mut Self| tray.send(TrayAction::TogglePause)),
+                ..Default::default()
+            }
+            .into(),
+            StandardItem {
+                label: "Apply saved profile".into(),
+                enabled: Profile::exists(),
+                activate: Box::new(|tray: &mut Self| tray.send(TrayAction::ApplyProfile)),
+                ..Default::default()
+            }
+            .into(),
+            StandardItem {
+                label: "Save limits as profile".into(),
+                activate: Box::new(|tray: &mut Self| tray.send(TrayAction::SaveProfile)),
+                ..Default::default()
+            }
+            .into(),
+            MenuItem::Separator,
+            StandardItem {
+                label: "Quit".into(),
+                icon_name: "application-exit".into(),
+                activate: Box::new(|tray: &mut Self| tray.send(TrayAction::Quit)),
+                ..Default::default()
+            }
+            .into(),
+        ]
+    }
+    fn watcher_online(&self) {
+        self.shown.store(true, Ordering::Release);
+    }
+    fn watcher_offine(&self) -> bool {
+        self.shown.store(false, Ordering::Release);
+        // keep the service, the icon comes back with the tray host
+        true
+    }
+}
+
+/// Returns the handle of the tray and whether a tray host shows it
+pub fn spawn_tray(tx: glib::Sender<UpdateGuiMessage>) -> (ksni::Handle<Tray>, Arc<AtomicBool>) {
+    let shown = Arc::new(AtomicBool::new(false));
+    let service = ksni::TrayService::new(Tray {
+        tx,
+        global_speed: (0., 0.),
+        global_limit: false,
+        paused: false,
+        shown: shown.clone(),
+    });
+    let handle = service.handle();
+    let shown_c = shown.clone();
+    std::thread::spawn(move || {
+        // without a session bus there is only the window
+        if let Err(e) = service.run() {
+            shown_c.store(false, Ordering::Release);
+            eprintln!("Error: no tray icon: {}", e);
+        }
+    });
+    (handle, shown)
+}
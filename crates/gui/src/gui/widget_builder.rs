@@ -1,33 +1,155 @@
-use super::Message;
-use crate::utils::ifconfig;
+use super::chart::{Chart, Limits};
+use super::profile::RowSettings;
+use crate::desktop_entries::DesktopEntry;
+use eltrafico_client::{ifconfig, Client, Kind, LimitConfig, ProgramInfo};
 use glib::clone;
-use glib::object::Cast;
 use gtk::prelude::*;
 use gtk::*;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::io::Write;
 use std::rc::Rc;
 
-type SharedStdinHandle = Rc<RefCell<Option<std::process::ChildStdin>>>;
+const UNITS: [&str; 3] = ["Bps", "Kbps", "Mbps"];
 
 fn create_unit_widget() -> ComboBoxText {
     let unit = ComboBoxText::new();
-    unit.append(None, "Bps");
-    unit.append(None, "Kbps");
-    unit.append(None, "Mbps");
+    for name in UNITS {
+        unit.append(None, name);
+    }
     unit.set_active(Some(1));
     unit
 }
 fn get_unit(widget: &ComboBoxText) -> String {
     widget.active_text().unwrap().to_string()
 }
+/// The value of the spin button in KB/sec, the speeds are shown in it
+fn kb_per_sec(value: &SpinButton, unit: &ComboBoxText) -> f32 {
+    let factor = match get_unit(unit).as_str() {
+        "Bps" => 0.001,
+        "Mbps" => 1000.,
+        _ => 1.,
+    };
+    value.value() as f32 * factor
+}
+
+/// The widgets of a row that are driven from outside of it
+#[derive(Clone)]
+pub struct Row {
+    pub widget: ScrolledWindow,
+    icon: Image,
+    title: Label,
+    speed: Label,
+    chart: Chart,
+    set_btn: CheckButton,
+    block_btn: CheckButton,
+    /// down, up, down min and up min
+    limits: [(SpinButton, ComboBoxText); 4],
+}
+
+impl Row {
+    /// The shown name, and the name from ss when they differ
+    pub fn title(&self) -> String {
+        self.title.text().to_string()
+    }
+
+    /// Show the application behind the program, its pids, exe and cmdline in a tooltip
+    pub fn set_program(&self, program: &ProgramInfo, entry: Option<&DesktopEntry>) {
+        let name = glib::markup_escape_text(&program.name);
+        match entry {
+            Some(entry) if entry.name != program.name => self.title.set_markup(&format!(
+                "<b>{}</b>\n<small>{}</small>",
+                glib::markup_escape_text(&entry.name),
+                name
+            )),
+            _ => self.title.set_markup(&format!("<b>{}</b>", name)),
+        }
+
+        let icon = entry.and_then(|entry| entry.icon.as_deref());
+        match icon {
+            Some(path) if path.starts_with('/') => {
+                match gdk_pixbuf::Pixbuf::from_file_at_scale(path, 24, 24, true) {
+                    Ok(pixbuf) => self.icon.set_from_pixbuf(Some(&pixbuf)),
+                    Err(_) => self.icon.set_from_icon_name(
+                        Some("application-x-executable"),
+                        IconSize::LargeToolbar,
+                    ),
+                }
+            }
+            _ => self.icon.set_from_icon_name(
+                Some(icon.unwrap_or("application-x-executable")),
+                IconSize::LargeToolbar,
+            ),
+        }
+
+        let pids: Vec<String> = program.pids.iter().map(ToString::to_string).collect();
+        let tooltip = format!(
+            "Pids: {}\nExe: {}\nCommand: {}",
+            pids.join(", "),
+            program.exe.as_deref().unwrap_or("unknown"),
+            program.cmdline.as_deref().unwrap_or("unknown")
+        );
+        self.title.set_tooltip_text(Some(&tooltip));
+        self.icon.set_tooltip_text(Some(&tooltip));
+    }
+
+    /// Show the current speed, (up, down) in KB/sec
+    pub fn set_speed(&self, speed: (f32, f32)) {
+        self.speed.set_label(&format!(
+            "Down: {:.2} KB/sec Up: {:.2} KB/sec",
+            speed.1, speed.0
+        ));
+        self.chart.push(speed);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.set_btn.is_active()
+    }
+
+    /// Applying or removing the limits sends them to eltrafico_tc
+    pub fn set_active(&self, active: bool) {
+        self.set_btn.set_active(active);
+    }
+
+    pub fn connect_active_toggled(&self, f: impl Fn(bool) + 'static) {
+        self.set_btn.connect_toggled(move |btn| f(btn.is_active()));
+    }
+
+    pub fn settings(&self) -> RowSettings {
+        RowSettings {
+            limits: self
+                .limits
+                .each_ref()
+                .map(|(value, unit)| (value.value(), get_unit(unit))),
+            active: self.set_btn.is_active(),
+            blocked: self.block_btn.is_active(),
+        }
+    }
+
+    /// Show the settings and send them to eltrafico_tc
+    pub fn apply(&self, settings: &RowSettings) {
+        // changing a limit deactivates the row, activate it after
+        for ((value, unit), (new_value, new_unit)) in self.limits.iter().zip(&settings.limits) {
+            value.set_value(*new_value);
+            if let Some(position) = UNITS.iter().position(|name| name == new_unit) {
+                unit.set_active(Some(position as u32));
+            }
+        }
+        self.set_btn.set_active(settings.active);
+        self.block_btn.set_active(settings.blocked);
+    }
+
+    /// Cut the program off the network, its limits come back when it is unblocked
+    pub fn set_blocked(&self, blocked: bool) {
+        self.block_btn.set_active(blocked);
+    }
+
+    /// Remove the limits and the block, `apply` brings them back
+    pub fn pause(&self) {
+        self.block_btn.set_active(false);
+        self.set_btn.set_active(false);
+    }
+}
 
-pub fn create_row(
-    name: Option<&str>,
-    stdin: SharedStdinHandle,
-    global: bool,
-) -> gtk::ScrolledWindow {
+pub fn create_row(name: Option<&str>, client: Rc<Client>, global: bool) -> Row {
     let advanced = std::env::args().any(|s| &s == "--advanced");
     //TODO switch to a gtk::grid
     let name = name.unwrap_or("?").to_string();
@@ -36,6 +158,7 @@ pub fn create_row(
     title.set_markup(&print_name);
     title.set_width_chars(20);
     title.set_halign(gtk::Align::Start);
+    let icon = Image::new();
 
     let current_speed = Label::new(None);
     let down = Label::new(Some("Down: "));
@@ -66,9 +189,14 @@ pub fn create_row(
     up_min_value.set_placeholder_text(Some("None"));
 
     let set_btn = CheckButton::new();
+    let block_btn = CheckButton::new();
 
     // send the program name and its limits to the limiter thread
-    set_btn.connect_toggled(clone!(@strong down_value, @strong up_value, @strong down_unit, @strong up_unit ,@strong up_min_value, @strong down_min_value, @strong down_min_unit, @strong up_min_unit=> move |btn| {
+    set_btn.connect_toggled(clone!(@strong client, @strong name, @strong block_btn, @strong down_value, @strong up_value, @strong down_unit, @strong up_unit ,@strong up_min_value, @strong down_min_value, @strong down_min_unit, @strong up_min_unit=> move |btn| {
+        // a blocked program ignores its limits, they are sent again when it gets unblocked
+        if block_btn.is_active() {
+            return;
+        }
         let (up, down, up_min,down_min) = if btn.is_active() {
             let down = {
                 let val = down_value.text().to_string();
@@ -92,21 +220,47 @@ pub fn create_row(
         };
 
         if global {
-            writeln!(
-                stdin.borrow_mut().as_mut().unwrap(),
-                "{}",
-                Message::Global((down, up))
-            )
-            .expect("Error sending Global limit to eltrafico_tc");
+            let config = LimitConfig {
+                download_rate: down,
+                upload_rate: up,
+                ..Default::default()
+            };
+            client.set_global(config)
+                .expect("Error sending Global limit to eltrafico_tc");
         } else {
-            writeln!(
-                stdin.borrow_mut().as_mut().unwrap(),
-                "{}",
-                Message::Program((name.clone(), (down, up, down_min,up_min)))
-            )
-            .expect("Error sending Program limit to eltrafico_tc");
+            let config = LimitConfig {
+                download_rate: down,
+                upload_rate: up,
+                download_minimum_rate: down_min,
+                upload_minimum_rate: up_min,
+                ..Default::default()
+            };
+            client.set_program(&name, config)
+                .expect("Error sending Program limit to eltrafico_tc");
+        }
+
+    }));
+
+    // let the program through while in allowlist mode
+    let allow_btn = CheckButton::new();
+    allow_btn.connect_toggled(clone!(@strong client, @strong name => move |btn| {
+        if btn.is_active() {
+            client.allow(&name)
+        } else {
+            client.disallow(&name)
         }
+        .expect("Error sending Allow to eltrafico_tc");
+    }));
 
+    // cut the program off the network, unblocking restores its limits
+    block_btn.connect_toggled(clone!(@strong set_btn => move |btn| {
+        if btn.is_active() {
+            client
+                .block(&name)
+                .expect("Error sending Block to eltrafico_tc");
+        } else {
+            set_btn.toggled();
+        }
     }));
 
     // Disable limit on variables changes
@@ -135,11 +289,28 @@ pub fn create_row(
         set_btn.set_active(false);
     }));
 
+    // mark the limits that are applied, a blocked program has none
+    let chart = Chart::new(
+        clone!(@weak set_btn, @weak block_btn, @weak down_value, @weak up_value, @weak down_unit, @weak up_unit => @default-return Limits::default(), move || {
+            if !set_btn.is_active() || block_btn.is_active() {
+                return Limits::default();
+            }
+            (
+                Some(kb_per_sec(&up_value, &up_unit)),
+                Some(kb_per_sec(&down_value, &down_unit)),
+            )
+        }),
+    );
+    set_btn.connect_toggled(clone!(@strong chart => move |_| chart.widget().queue_draw()));
+    block_btn.connect_toggled(clone!(@strong chart => move |_| chart.widget().queue_draw()));
+
     let hbox = Box::new(Orientation::Horizontal, 20);
     // TODO: make the label fixed size
+    hbox.pack_start(&icon, false, false, 0);
     hbox.pack_start(&title, false, false, 10);
 
     hbox.add(&current_speed);
+    hbox.add(chart.widget());
     hbox.add(&down);
     hbox.add(&down_value);
     hbox.add(&down_unit);
@@ -159,76 +330,104 @@ pub fn create_row(
     hbox.add(&Label::new(Some("Active:")));
 
     hbox.add(&set_btn);
+
+    if !global {
+        hbox.add(&Label::new(Some("Block:")));
+        hbox.add(&block_btn);
+        hbox.add(&Label::new(Some("Allow:")));
+        hbox.add(&allow_btn);
+    }
     let scrolled_box: ScrolledWindow = ScrolledWindow::new::<Adjustment, Adjustment>(None, None);
     scrolled_box.add(&hbox);
-    scrolled_box
+    Row {
+        widget: scrolled_box,
+        icon,
+        title,
+        speed: current_speed,
+        chart,
+        set_btn,
+        block_btn,
+        limits: [
+            (down_value, down_unit),
+            (up_value, up_unit),
+            (down_min_value, down_min_unit),
+            (up_min_value, up_min_unit),
+        ],
+    }
 }
 
-pub fn update_gui_program_speed(app_box: gtk::Box, programs_speed: HashMap<String, (f32, f32)>) {
-    let programs = app_box.children();
-    for program in programs {
-        let program: gtk::ScrolledWindow = program.clone().downcast().unwrap();
-        let program: gtk::Viewport = program.children()[0].clone().downcast().unwrap();
-        let program: gtk::Box = program.child().unwrap().downcast().unwrap();
-        let program = program.children();
-        let name: gtk::Label = program[0].clone().downcast().unwrap();
-        let name = name.text().to_string();
-        let speed: gtk::Label = program[1].clone().downcast().unwrap();
-        if programs_speed.contains_key(&name) {
-            speed.set_label(&format!(
-                "Down: {:.2} KB/sec Up: {:.2} KB/sec",
-                programs_speed[&name].1, programs_speed[&name].0
-            ));
-        } else {
-            // Program data wasent sent from nethogs thread
-            // That means its not active network wise anymore
-            // Update label as feedback
-            speed.set_label("Down: 0 KB/sec Up: 0 KB/se");
+/// Fill the combobox with the interfaces and their current state, keeping the selection
+fn refresh_interfaces(combobox: &ComboBoxText) {
+    let active = combobox.active_id();
+    combobox.remove_all();
+    // follow the interface that carries the default route
+    combobox.append(Some("auto"), "auto");
+    for interface in ifconfig().expect("Failed to get network interfaces") {
+        if interface.kind != Kind::Ifb {
+            combobox.append(
+                Some(&interface.name),
+                &format!("{} ({})", interface.name, interface.summary()),
+            );
         }
     }
+    combobox.set_active_id(active.as_deref());
 }
 
-pub fn update_gui_global_speed(scrolled_box: gtk::ScrolledWindow, global_speed: (f32, f32)) {
-    let viewport: gtk::Viewport = scrolled_box.children()[0].clone().downcast().unwrap();
-    let r#box: gtk::Box = viewport.child().unwrap().downcast().unwrap();
-
-    let speed: gtk::Label = r#box.children()[1].clone().downcast().unwrap();
-    speed.set_label(&format!(
-        "Down: {:.2} KB/sec Up: {:.2} KB/sec",
-        global_speed.1, global_speed.0
-    ));
-}
-
-pub fn create_interface_row(stdin: SharedStdinHandle) -> Box {
+/// Returns the row and the label that shows the interface followed in auto mode
+pub fn create_interface_row(client: Rc<Client>) -> (Box, Label) {
     let label = Label::new(Some("Interface: "));
     let combobox = ComboBoxText::new();
-    let interfaces = ifconfig().expect("Failed to get network interfaces");
-
-    interfaces
-        .into_iter()
-        .enumerate()
-        .for_each(|(idx, interface)| {
-            if !interface.name.starts_with("ifb") {
-                combobox.insert_text(idx as i32, &interface.name);
+    refresh_interfaces(&combobox);
+    let followed_interface = Label::new(None);
+
+    // refreshing the list selects the current interface again, don't resend it
+    let selected_interface: Rc<RefCell<Option<String>>> = Default::default();
+    combobox.connect_changed(
+        clone!(@strong client, @strong followed_interface => move |combobox| {
+            let interface = match combobox.active_id() {
+                Some(interface) => interface.to_string(),
+                None => return,
+            };
+            if selected_interface.borrow().as_ref() == Some(&interface) {
+                return;
             }
-        });
-
-    combobox.connect_changed(move |combobox| {
-        let selected_interface = combobox
-            .active_text()
-            .expect("Error reading interface name")
-            .to_string();
-        writeln!(
-            stdin.borrow_mut().as_mut().unwrap(),
-            "{}",
-            Message::Interface(selected_interface)
-        )
-        .expect("Error sending interface to eltrafico_tc");
+            selected_interface.replace(Some(interface.clone()));
+            followed_interface.set_text("");
+            client
+                .set_interface(&interface)
+                .expect("Error sending interface to eltrafico_tc");
+        }),
+    );
+
+    // keep the interfaces state up to date, but not while the user is choosing one
+    glib::timeout_add_seconds_local(
+        5,
+        clone!(@weak combobox => @default-return glib::Continue(false), move || {
+            if !combobox.is_popup_shown() {
+                refresh_interfaces(&combobox);
+            }
+            glib::Continue(true)
+        }),
+    );
+    let refresh_btn = Button::with_label("Refresh");
+    refresh_btn.connect_clicked(clone!(@weak combobox => move |_| {
+        refresh_interfaces(&combobox);
+    }));
+
+    // block every program that isn't explicitly allowed
+    let allowlist_btn = CheckButton::with_label("Allowlist mode");
+    allowlist_btn.connect_toggled(move |btn| {
+        client
+            .set_allowlist(btn.is_active())
+            .expect("Error sending allowlist mode to eltrafico_tc");
     });
 
     let interface_row = Box::new(Orientation::Horizontal, 10);
     interface_row.add(&label);
     interface_row.add(&combobox);
+    interface_row.add(&refresh_btn);
+    interface_row.add(&followed_interface);
+    interface_row.add(&allowlist_btn);
 
-    interface_row
+    (interface_row, followed_interface)
 }
@@ -1,6 +1,7 @@
 #[cfg(not(unix))]
 compile_error!("This program is unix only for now");
 
+mod desktop_entries;
 mod gui;
 mod utils;
 use utils::check_for_dependencies;
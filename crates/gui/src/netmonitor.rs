@@ -1,26 +1,36 @@
 use crate::gui::UpdateGuiMessage;
-use crate::utils::check_for_dependencies;
 use crate::CatchAll;
 use glib::Sender;
-use std::thread;
-mod bandwhich;
-mod nethogs;
-use bandwhich::bandwhich;
-use nethogs::nethogs;
+use std::sync::mpsc;
 
+/// Forward the speeds of the monitor to the gui thread
 pub fn netmonitor(tx: Sender<UpdateGuiMessage>) -> CatchAll<()> {
-    if check_for_dependencies(&["bandwhich"]).is_ok() {
-        thread::spawn(|| {
-            if let Err(e) = bandwhich(tx) {
-                panic!("Bandwhich error: {}", e);
+    let (tx_speeds, rx_speeds) = mpsc::channel();
+    eltrafico_client::netmonitor::netmonitor(tx_speeds)?;
+    std::thread::spawn(move || {
+        for speeds in rx_speeds {
+            let sent = tx
+                .send(UpdateGuiMessage::CurrentGlobalSpeed(speeds.global))
+                .and_then(|_| tx.send(UpdateGuiMessage::CurrentProgramSpeed(speeds.programs)));
+            if sent.is_err() {
+                break;
             }
-        });
-    } else if check_for_dependencies(&["nethogs"]).is_ok() {
-        thread::spawn(|| {
-            if let Err(e) = nethogs(tx) {
-                panic!("Nethogs error: {}", e);
-            }
-        });
-    }
+        }
+    });
     Ok(())
 }
+
+#[test]
+#[ignore = "runs the gtk main loop till it is closed, needs a display and bandwhich"]
+fn t_bandwhich() {
+    gtk::init().unwrap();
+
+    let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+    netmonitor(tx).unwrap();
+
+    rx.attach(None, move |message| {
+        dbg!(message);
+        glib::Continue(true)
+    });
+    gtk::main();
+}
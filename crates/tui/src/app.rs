@@ -0,0 +1,435 @@
+use eltrafico_client::netmonitor::{Speed, Speeds};
+use eltrafico_client::{ifconfig, Kind, Status, AUTO_INTERFACE};
+use eltrafico_client::{BackendEvent, Client, LimitConfig, ProgramRule, ProgramStatus, Result};
+use ratatui::crossterm::event::{KeyCode, KeyEvent};
+
+/// The editable fields of a row, in the order of the columns
+pub const FIELDS: [&str; 6] = ["Down", "Up", "Down Min", "Up Min", "Down Prio", "Up Prio"];
+const DOWN_PRIO: usize = 4;
+
+/// The global row is always the first one
+pub const GLOBAL: usize = 0;
+
+/// A program or the global limit, like `widget_builder::create_row`
+pub struct Row {
+    pub name: String,
+    /// the fields as typed, an empty field is not sent
+    pub limits: [String; 6],
+    pub active: bool,
+    pub blocked: bool,
+    pub allowed: bool,
+    /// the program had no connections for a while
+    pub idle: bool,
+    pub speed: Speed,
+}
+
+impl Row {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            // better default to a working amount
+            limits: [
+                "100kbps".into(),
+                "100kbps".into(),
+                "1kbps".into(),
+                "1kbps".into(),
+                String::new(),
+                String::new(),
+            ],
+            active: false,
+            blocked: false,
+            allowed: false,
+            idle: false,
+            speed: (0., 0.),
+        }
+    }
+
+    /// Fill the fields with the limits eltrafico-tc already has
+    ///
+    /// A row without limits keeps the defaults, otherwise the unset fields are left empty
+    fn set_config(&mut self, config: &LimitConfig) {
+        let fields = [
+            config.download_rate.clone(),
+            config.upload_rate.clone(),
+            config.download_minimum_rate.clone(),
+            config.upload_minimum_rate.clone(),
+            config.download_priority.map(|p| p.to_string()),
+            config.upload_priority.map(|p| p.to_string()),
+        ];
+        self.active = fields.iter().any(Option::is_some);
+        if self.active {
+            for (field, value) in self.limits.iter_mut().zip(fields) {
+                *field = value.unwrap_or_default();
+            }
+        }
+    }
+
+    fn from_status(program: &ProgramStatus) -> Self {
+        let mut row = Row::new(&program.name);
+        match &program.rule {
+            ProgramRule::Block => row.blocked = true,
+            ProgramRule::Limit { config, .. } => row.set_config(config),
+        }
+        row.allowed = program.allowed;
+        row.idle = program.idle;
+        row
+    }
+
+    pub fn config(&self) -> LimitConfig {
+        let field = |i: usize| Some(self.limits[i].clone()).filter(|f| !f.is_empty());
+        let priority = |i: usize| field(i).and_then(|p| p.parse().ok());
+        LimitConfig {
+            download_rate: field(0),
+            upload_rate: field(1),
+            download_minimum_rate: field(2),
+            upload_minimum_rate: field(3),
+            download_priority: priority(4),
+            upload_priority: priority(5),
+        }
+    }
+}
+
+/// Check a field before it is sent, tc rejects the whole limit for one bad field
+fn validate(field: usize, value: &str) -> std::result::Result<(), String> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    if field >= DOWN_PRIO {
+        return value
+            .parse::<usize>()
+            .map(|_| ())
+            .map_err(|_| format!("{} must be a number", FIELDS[field]));
+    }
+    const UNITS: [&str; 9] = [
+        "", "bit", "kbit", "mbit", "gbit", "bps", "kbps", "mbps", "gbps",
+    ];
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(unit_start);
+    if amount.parse::<f64>().is_err() || !UNITS.contains(&unit.to_lowercase().as_str()) {
+        return Err(format!(
+            "{} must be a rate like 100kbps or 2mbit",
+            FIELDS[field]
+        ));
+    }
+    Ok(())
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Focus {
+    Interfaces,
+    Rows,
+}
+
+pub struct App {
+    client: Client,
+    /// we spawned eltrafico-tc, quitting stops it
+    owned: bool,
+    /// name and state, auto comes first
+    pub interfaces: Vec<(String, String)>,
+    pub selected_interface: usize,
+    pub status: Status,
+    /// the interface followed in auto mode
+    pub followed: Option<String>,
+    pub rows: Vec<Row>,
+    pub selected_row: usize,
+    pub selected_field: usize,
+    pub focus: Focus,
+    /// the text of the field being edited
+    pub input: Option<String>,
+    /// the last error or what the keys do
+    pub message: String,
+    pub quit: bool,
+}
+
+impl App {
+    /// Start from what eltrafico-tc already has, a daemon can have been running for a while
+    pub fn new(client: Client, owned: bool) -> Result<Self> {
+        let status = client.status()?;
+        let mut global = Row::new("global");
+        global.set_config(&status.global_limit);
+        let mut rows = vec![global];
+        rows.extend(client.programs()?.iter().map(Row::from_status));
+        let mut app = Self {
+            client,
+            owned,
+            interfaces: vec![],
+            selected_interface: 0,
+            status,
+            followed: None,
+            rows,
+            selected_row: GLOBAL,
+            selected_field: 0,
+            focus: Focus::Interfaces,
+            input: None,
+            message: String::new(),
+            quit: false,
+        };
+        app.refresh_interfaces();
+        Ok(app)
+    }
+
+    /// List the interfaces and their state, keeping the selection
+    pub fn refresh_interfaces(&mut self) {
+        let selected = self.interfaces.get(self.selected_interface).cloned();
+        self.interfaces = vec![(AUTO_INTERFACE.to_string(), "default route".to_string())];
+        self.interfaces.extend(interfaces());
+        self.selected_interface = selected
+            .and_then(|(name, _)| self.interfaces.iter().position(|(n, _)| *n == name))
+            .unwrap_or(0);
+    }
+
+    pub fn handle_event(&mut self, event: BackendEvent) -> Result<()> {
+        match event {
+            BackendEvent::ProgramEntry(program) => {
+                // a program that exited keeps its row and gets it back when it returns
+                match self.rows[GLOBAL + 1..]
+                    .iter_mut()
+                    .find(|row| row.name == program.name)
+                {
+                    Some(row) => row.idle = false,
+                    None => self.rows.push(Row::new(&program.name)),
+                }
+            }
+            BackendEvent::ProgramExit(program) => {
+                if let Some(row) = self.rows[GLOBAL + 1..]
+                    .iter_mut()
+                    .find(|row| row.name == program)
+                {
+                    row.idle = true;
+                }
+            }
+            BackendEvent::InterfaceChanged(interface) => {
+                self.followed = Some(interface);
+                self.status = self.client.status()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn update_speeds(&mut self, speeds: Speeds) {
+        self.rows[GLOBAL].speed = speeds.global;
+        for row in &mut self.rows[GLOBAL + 1..] {
+            // a program missing from the monitor is not active network wise anymore
+            row.speed = speeds.programs.get(&row.name).copied().unwrap_or((0., 0.));
+        }
+    }
+
+    /// Send the limits of the row, or clear them if the row isn't active
+    fn apply(&mut self, index: usize) -> Result<()> {
+        let row = &self.rows[index];
+        // a blocked program ignores its limits, they are sent again when it gets unblocked
+        if row.blocked {
+            return Ok(());
+        }
+        let config = if row.active {
+            row.config()
+        } else {
+            LimitConfig::default()
+        };
+        if index == GLOBAL {
+            self.client.set_global(config)
+        } else {
+            self.client.set_program(&row.name, config)
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) -> Result<()> {
+        if let Some(input) = &mut self.input {
+            match key.code {
+                KeyCode::Char(c) => input.push(c),
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Enter => {
+                    let value = input.trim().to_string();
+                    if let Err(e) = validate(self.selected_field, &value) {
+                        self.message = e;
+                        return Ok(());
+                    }
+                    self.input = None;
+                    self.message.clear();
+                    let row = &mut self.rows[self.selected_row];
+                    row.limits[self.selected_field] = value;
+                    // disable the limit on changes, like the gui does
+                    if row.active {
+                        row.active = false;
+                        self.apply(self.selected_row)?;
+                    }
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        match (self.focus, key.code) {
+            (_, KeyCode::Char('q')) => {
+                if self.owned {
+                    // eltrafico-tc sends Stopped once it cleaned up
+                    self.client.stop()?;
+                    self.message = "Stopping...".into();
+                } else {
+                    self.quit = true;
+                }
+            }
+            (_, KeyCode::Tab) => {
+                self.focus = match self.focus {
+                    Focus::Interfaces => Focus::Rows,
+                    Focus::Rows => Focus::Interfaces,
+                }
+            }
+            (_, KeyCode::Char('w')) => {
+                let allowlist = !self.status.allowlist;
+                self.client.set_allowlist(allowlist)?;
+                self.status = self.client.status()?;
+            }
+            (_, KeyCode::Char('r')) => self.refresh_interfaces(),
+            (Focus::Interfaces, KeyCode::Up | KeyCode::Char('k')) => {
+                self.selected_interface = self.selected_interface.saturating_sub(1);
+            }
+            (Focus::Interfaces, KeyCode::Down | KeyCode::Char('j')) => {
+                self.selected_interface =
+                    (self.selected_interface + 1).min(self.interfaces.len() - 1);
+            }
+            (Focus::Interfaces, KeyCode::Enter) => {
+                let interface = self.interfaces[self.selected_interface].0.clone();
+                self.followed = None;
+                self.client.set_interface(&interface)?;
+                self.status = self.client.status()?;
+                self.focus = Focus::Rows;
+            }
+            (Focus::Rows, KeyCode::Up | KeyCode::Char('k')) => {
+                self.selected_row = self.selected_row.saturating_sub(1);
+            }
+            (Focus::Rows, KeyCode::Down | KeyCode::Char('j')) => {
+                self.selected_row = (self.selected_row + 1).min(self.rows.len() - 1);
+            }
+            (Focus::Rows, KeyCode::Left | KeyCode::Char('h')) => {
+                self.selected_field = self.selected_field.saturating_sub(1);
+            }
+            (Focus::Rows, KeyCode::Right | KeyCode::Char('l')) => {
+                self.selected_field = (self.selected_field + 1).min(FIELDS.len() - 1);
+            }
+            (Focus::Rows, KeyCode::Enter) => {
+                self.input = Some(self.rows[self.selected_row].limits[self.selected_field].clone());
+            }
+            (Focus::Rows, KeyCode::Char(' ')) => {
+                let row = &mut self.rows[self.selected_row];
+                row.active = !row.active;
+                self.apply(self.selected_row)?;
+            }
+            // cut the program off the network, unblocking restores its limits
+            (Focus::Rows, KeyCode::Char('b')) if self.selected_row != GLOBAL => {
+                let row = &mut self.rows[self.selected_row];
+                row.blocked = !row.blocked;
+                if row.blocked {
+                    self.client.block(&row.name)?;
+                } else {
+                    self.apply(self.selected_row)?;
+                }
+            }
+            // let the program through while in allowlist mode
+            (Focus::Rows, KeyCode::Char('a')) if self.selected_row != GLOBAL => {
+                let row = &mut self.rows[self.selected_row];
+                row.allowed = !row.allowed;
+                if row.allowed {
+                    self.client.allow(&row.name)?;
+                } else {
+                    self.client.disallow(&row.name)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The network interfaces and their state, without the IFB devices we use
+fn interfaces() -> Vec<(String, String)> {
+    let Ok(interfaces) = ifconfig() else {
+        return vec![];
+    };
+    interfaces
+        .into_iter()
+        .filter(|interface| interface.kind != Kind::Ifb)
+        .map(|interface| {
+            let summary = interface.summary();
+            (interface.name, summary)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_config_skips_empty_fields() {
+        let mut row = Row::new("firefox");
+        row.limits[1] = String::new();
+        row.limits[4] = "2".into();
+        assert_eq!(
+            row.config(),
+            LimitConfig {
+                download_rate: Some("100kbps".into()),
+                upload_rate: None,
+                download_minimum_rate: Some("1kbps".into()),
+                upload_minimum_rate: Some("1kbps".into()),
+                download_priority: Some(2),
+                upload_priority: None,
+            }
+        );
+    }
+
+    #[test]
+    fn row_from_status() {
+        let program = ProgramStatus {
+            name: "curl".into(),
+            rule: ProgramRule::Limit {
+                config: LimitConfig {
+                    upload_rate: Some("2mbit".into()),
+                    ..Default::default()
+                },
+                interfaces: None,
+            },
+            allowed: true,
+            idle: false,
+        };
+        let row = Row::from_status(&program);
+        assert!(row.active && row.allowed && !row.blocked);
+        assert_eq!(row.limits[0], "");
+        assert_eq!(row.limits[1], "2mbit");
+        assert_eq!(row.limits[2], "");
+        // so the same limits are sent back
+        assert_eq!(
+            row.config(),
+            LimitConfig {
+                upload_rate: Some("2mbit".into()),
+                ..Default::default()
+            }
+        );
+        // a program without limits isn't active and keeps the defaults
+        let program = ProgramStatus {
+            rule: ProgramRule::default(),
+            ..program
+        };
+        let row = Row::from_status(&program);
+        assert!(!row.active);
+        assert_eq!(row.limits[0], "100kbps");
+    }
+
+    #[test]
+    fn validate_fields() {
+        assert!(validate(0, "100kbps").is_ok());
+        assert!(validate(1, "2.5Mbit").is_ok());
+        assert!(validate(2, "").is_ok());
+        assert!(validate(3, "1000").is_ok());
+        assert!(validate(0, "fast").is_err());
+        assert!(validate(0, "10 kbit").is_err());
+        assert!(validate(0, "10kb").is_err());
+        assert!(validate(4, "3").is_ok());
+        assert!(validate(5, "high").is_err());
+    }
+}
@@ -0,0 +1,95 @@
+#[cfg(not(unix))]
+compile_error!("This program is unix only for now");
+
+mod app;
+mod ui;
+use app::App;
+use eltrafico_client::netmonitor::netmonitor;
+use eltrafico_client::{check_for_dependencies, find_eltrafico_tc, Client, Event, Result};
+use ratatui::crossterm::event::{self, Event as TermEvent, KeyEventKind};
+use ratatui::DefaultTerminal;
+use std::sync::mpsc;
+use std::time::Duration;
+
+const DEPENDENCIES: [&str; 3] = ["tc", "ss", "ip"];
+
+const USAGE: &str = "Usage: tui [--socket | --eltrafico-tc PATH]
+
+Limit the traffic of programs from the terminal.
+
+Options:
+    --socket             Join the eltrafico-tc running with --socket, quitting leaves it running
+    --eltrafico-tc PATH  Spawn this eltrafico-tc instead of the one in $PATH
+    -h, --help           Print this help";
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.contains(&"-h".to_string()) || args.contains(&"--help".to_string()) {
+        println!("{USAGE}");
+        std::process::exit(0);
+    }
+    let socket = args.contains(&"--socket".to_string());
+
+    // the password prompt of pkexec needs the terminal, start eltrafico-tc before taking it over
+    let result = connect(socket).and_then(|client| {
+        let events = client.subscribe();
+        let (tx_speeds, speeds) = mpsc::channel();
+        netmonitor(tx_speeds)?;
+        let app = App::new(client, !socket)?;
+
+        let mut terminal = ratatui::init();
+        let result = run(&mut terminal, app, events, speeds);
+        ratatui::restore();
+        result
+    });
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn connect(socket: bool) -> Result<Client> {
+    if socket {
+        return Client::connect(eltrafico_client::SOCKET_PATH);
+    }
+    check_for_dependencies(&DEPENDENCIES)?;
+    Client::spawn(find_eltrafico_tc()?)
+}
+
+fn run(
+    terminal: &mut DefaultTerminal,
+    mut app: App,
+    events: mpsc::Receiver<Event>,
+    speeds: mpsc::Receiver<eltrafico_client::netmonitor::Speeds>,
+) -> Result<()> {
+    loop {
+        for event in events.try_iter() {
+            match event {
+                Event::Backend(event) => app.handle_event(event)?,
+                Event::Stopped => return Ok(()),
+                Event::Died => {
+                    return Err(
+                        "eltrafico_tc exited unexpectedly, nothing is limited anymore".into(),
+                    )
+                }
+            }
+        }
+        if let Some(speeds) = speeds.try_iter().last() {
+            app.update_speeds(speeds);
+        }
+        if app.quit {
+            return Ok(());
+        }
+
+        terminal.draw(|frame| ui::draw(frame, &mut app))?;
+
+        // redraw at least every 200ms for the events and the speeds
+        if event::poll(Duration::from_millis(200))? {
+            if let TermEvent::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    app.handle_key(key)?;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::app::{App, Focus, Row, FIELDS, GLOBAL};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Cell, List, ListItem, ListState, Paragraph};
+use ratatui::widgets::{Table, TableState};
+use ratatui::Frame;
+
+const HELP: &str = "Tab switch pane · ↑↓←→ move · Enter edit/choose · Space limit · b block · a allow · w allowlist · r refresh · q quit";
+
+pub fn draw(frame: &mut Frame, app: &mut App) {
+    let interfaces_height = app.interfaces.len().min(6) as u16 + 2;
+    let [interfaces, rows, footer] = Layout::vertical([
+        Constraint::Length(interfaces_height),
+        Constraint::Min(4),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    draw_interfaces(frame, app, interfaces);
+    draw_rows(frame, app, rows);
+
+    let footer_text = if app.message.is_empty() {
+        HELP
+    } else {
+        &app.message
+    };
+    frame.render_widget(
+        Paragraph::new(footer_text).style(Style::default().fg(Color::DarkGray)),
+        footer,
+    );
+}
+
+fn pane(title: String, focused: bool) -> Block<'static> {
+    let style = if focused {
+        Style::default().fg(Color::Cyan)
+    } else {
+        Style::default()
+    };
+    Block::default()
+        .borders(Borders::ALL)
+        .border_style(style)
+        .title(title)
+}
+
+fn draw_interfaces(frame: &mut Frame, app: &App, area: ratatui::layout::Rect) {
+    let shaped: Vec<&str> = app
+        .status
+        .interfaces
+        .iter()
+        .map(|i| i.name.as_str())
+        .collect();
+    let auto = app.status.auto_interface;
+    let items: Vec<ListItem> = app
+        .interfaces
+        .iter()
+        .map(|(name, state)| {
+            let mut line = vec![Span::raw(format!("{name} ({state})"))];
+            let active = if name == eltrafico_client::AUTO_INTERFACE {
+                auto
+            } else {
+                !auto && shaped.contains(&name.as_str())
+            };
+            if active {
+                line.push(Span::styled(" shaped", Style::default().fg(Color::Green)));
+            }
+            if name == eltrafico_client::AUTO_INTERFACE && auto {
+                if let Some(followed) = &app.followed {
+                    line.push(Span::raw(format!(" · Following: {followed}")));
+                }
+            }
+            ListItem::new(Line::from(line))
+        })
+        .collect();
+
+    let mut title = " Interfaces ".to_string();
+    if app.status.allowlist {
+        title.push_str("· Allowlist mode ");
+    }
+    let list = List::new(items)
+        .block(pane(title, app.focus == Focus::Interfaces))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    let mut state = ListState::default().with_selected(Some(app.selected_interface));
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+fn check(on: bool) -> &'static str {
+    if on {
+        "[x]"
+    } else {
+        "[ ]"
+    }
+}
+
+fn speed(kb: f32) -> String {
+    format!("{kb:.2} KB/sec")
+}
+
+fn draw_rows(frame: &mut Frame, app: &mut App, area: ratatui::layout::Rect) {
+    let header = ["Name", "Down/s", "Up/s"]
+        .into_iter()
+        .chain(FIELDS)
+        .chain(["Active", "Block", "Allow"])
+        .map(|title| Cell::from(title).style(Style::default().add_modifier(Modifier::BOLD)));
+
+    let editing_row = app.focus == Focus::Rows;
+    let rows = app.rows.iter().enumerate().map(|(index, row)| {
+        let Row {
+            name,
+            limits,
+            active,
+            blocked,
+            allowed,
+            idle,
+            speed: (up, down),
+        } = row;
+        let mut cells = vec![
+            Cell::from(name.as_str()),
+            Cell::from(speed(*down)),
+            Cell::from(speed(*up)),
+        ];
+        for (field, value) in limits.iter().enumerate() {
+            let selected = editing_row && index == app.selected_row && field == app.selected_field;
+            let cell = match &app.input {
+                Some(input) if selected => Cell::from(format!("{input}_"))
+                    .style(Style::default().fg(Color::Black).bg(Color::Yellow)),
+                _ if selected => Cell::from(value.as_str())
+                    .style(Style::default().add_modifier(Modifier::REVERSED)),
+                _ => Cell::from(value.as_str()),
+            };
+            cells.push(cell);
+        }
+        cells.push(Cell::from(check(*active)));
+        if index != GLOBAL {
+            cells.push(Cell::from(check(*blocked)));
+            cells.push(Cell::from(check(*allowed)));
+        }
+
+        let style = if index == GLOBAL {
+            Style::default().add_modifier(Modifier::BOLD)
+        } else if *idle {
+            // the program exited, its limits are still applied if it returns
+            Style::default().fg(Color::DarkGray)
+        } else {
+            Style::default()
+        };
+        ratatui::widgets::Row::new(cells).style(style)
+    });
+
+    let widths = [
+        Constraint::Length(20),
+        Constraint::Length(16),
+        Constraint::Length(16),
+    ]
+    .into_iter()
+    .chain([Constraint::Length(10); 6])
+    .chain([Constraint::Length(6); 3]);
+    let table = Table::new(rows, widths)
+        .header(ratatui::widgets::Row::new(header))
+        .block(pane(" Programs ".to_string(), editing_row))
+        .row_highlight_style(Style::default().bg(Color::DarkGray).fg(Color::White));
+    let mut state = TableState::default().with_selected(Some(app.selected_row));
+    frame.render_stateful_widget(table, area, &mut state);
+}
@@ -32,6 +32,8 @@ export class ElTrafico {
     }).spawn();
     this.#reader = this.#tc.stdout.getReader();
     this.#writer = this.#tc.stdin.getWriter();
+    // protocol version 2 sends ProgramEntry as json
+    this.#write("Hello: 2");
   }
   async limitGlobal(global: Omit<Process, "match">) {
     const startMsg = "Global: ";
@@ -42,19 +44,37 @@ export class ElTrafico {
   }
   async limit(process: Process) {
     //TODO: use all match names
-    const startMsg = `Program: ${utn(process.match[0].name)}`;
+    const startMsg = `Program: ${quoteName(utn(process.match[0].name))}`;
     const limitAction = `${startMsg} ${utn(process.download)} ${
       utn(process.upload)
     } ${utn(process["download-minimum"])} ${utn(process["upload-minimum"])}`;
 
     await this.#write(limitAction);
   }
+  async block(name: string) {
+    await this.#write(`Block: ${name}`);
+  }
+  async allowlist(on: boolean) {
+    await this.#write(`Allowlist: ${on ? "on" : "off"}`);
+  }
+  async allow(name: string) {
+    await this.#write(`Allow: ${name}`);
+  }
+  async disallow(name: string) {
+    await this.#write(`Disallow: ${name}`);
+  }
   async stop() {
     await this.#write("Stop");
   }
   async interface(name: string) {
     await this.#write(`Interface: ${name}`);
   }
+  async addInterface(name: string) {
+    await this.#write(`AddInterface: ${name}`);
+  }
+  async removeInterface(name: string) {
+    await this.#write(`RemoveInterface: ${name}`);
+  }
   async poll() {
     const data = await this.#read();
 
@@ -62,8 +82,10 @@ export class ElTrafico {
       return { stop: true };
     }
 
-    return (data.split("\n").filter((l) => l).map((line) => {
-      return { name: line.split("ProgramEntry: ")[1] };
+    return (data.split("\n").filter((l) => l.startsWith("ProgramEntry: ")).map((line) => {
+      // the entry is json: name, pids, exe and cmdline
+      const program = JSON.parse(line.slice("ProgramEntry: ".length));
+      return { name: program.name };
     }));
   }
   async #read() {
@@ -88,3 +110,11 @@ function utn(
   if (maybeValue === undefined) return "None";
   return maybeValue;
 }
+
+/** Names with whitespace are sent as json strings */
+function quoteName(name: string) {
+  if (name === "" || name.startsWith('"') || /\s/.test(name)) {
+    return JSON.stringify(name);
+  }
+  return name;
+}